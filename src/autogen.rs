@@ -0,0 +1,298 @@
+//! Parser for autogens, such as `--KeyToKey-- KeyCode::A, ModifierFlag::SHIFT_L | ModifierFlag::NONE, KeyCode::B`
+//!
+//! An autogen is a type between dashes, followed by comma-separated arguments. Each argument is
+//! one or more symbols (`Namespace::NAME`, or `Namespace::RawValue::$number`) joined by `|`.
+//! Whitespace (including newlines) is allowed anywhere between tokens, and a trailing comma is
+//! ignored.
+
+use failure::Error;
+
+use std::fmt;
+
+/// Autogens whose arguments are free text instead of symbols
+const TEXT_TYPES: &[&str] = &["ShowStatusMessage"];
+
+/// A parsed autogen
+#[derive(Debug, Clone, PartialEq)]
+pub struct Autogen {
+    /// Name between the dashes, such as `KeyToKey`
+    pub type_: String,
+
+    /// Everything after the type (trimmed)
+    pub text: String,
+
+    /// Arguments (empty for autogens that take text)
+    pub args: Vec<Arg>,
+}
+
+/// A comma-separated argument
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arg {
+    /// Symbols joined by `|` (never empty)
+    pub symbols: Vec<Symbol>,
+}
+
+/// A name such as `KeyCode::A` or `KeyCode::RawValue::0x10`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    /// Segments between `::` (always at least two)
+    path: Vec<String>,
+
+    /// Written as a bare `VK_$mod` (kept for display)
+    bare: bool,
+}
+
+/// A group of arguments which starts with an `Option::$name` (except for the first one)
+#[derive(Debug)]
+pub struct Section<'a> {
+    pub option: Option<&'a Symbol>,
+    pub keys: Vec<KeyArg<'a>>,
+}
+
+/// A key with optional modifiers (the argument after it, if it consists of `ModifierFlag`s)
+#[derive(Debug)]
+pub struct KeyArg<'a> {
+    pub key: &'a Symbol,
+    pub modifiers: Option<&'a Arg>,
+}
+
+impl Autogen {
+    pub fn parse(s: &str) -> Result<Autogen, Error> {
+        let mut tokens = Tokenizer::new(s);
+        tokens.skip_whitespace();
+        let type_ = tokens.header()?;
+        let text = tokens.rest().trim().to_string();
+
+        let args = if TEXT_TYPES.contains(&&*type_) {
+            vec![]
+        } else {
+            tokens.args()?
+        };
+
+        Ok(Autogen { type_, text, args })
+    }
+
+    /// Split the arguments into sections at each `Option::$name`, and pair up keys and modifiers
+    pub fn sections(&self) -> Result<Vec<Section<'_>>, Error> {
+        sections(&self.args)
+    }
+}
+
+/// Parse a list of arguments on its own (as found in filters like `<modifier_only>`)
+pub fn parse_args(s: &str) -> Result<Vec<Arg>, Error> {
+    Tokenizer::new(s).args()
+}
+
+/// Split arguments into sections at each `Option::$name`, and pair up keys and modifiers
+pub fn sections(args: &[Arg]) -> Result<Vec<Section<'_>>, Error> {
+    let mut sections = vec![Section { option: None, keys: vec![] }];
+    let mut args = args.iter().peekable();
+    while let Some(arg) = args.next() {
+        let first = &arg.symbols[0];
+        if first.namespace() == "Option" {
+            sections.push(Section { option: Some(first), keys: vec![] });
+            continue;
+        }
+
+        if arg.symbols.len() > 1 {
+            bail!("Expected a single key, found `{}`", arg);
+        }
+        let modifiers = match args.peek() {
+            Some(next) if next.is_modifiers() => args.next(),
+            _ => None,
+        };
+        if let Some(section) = sections.last_mut() {
+            section.keys.push(KeyArg { key: first, modifiers });
+        }
+    }
+    Ok(sections)
+}
+
+impl Arg {
+    /// Whether this argument is a set of modifier flags
+    pub fn is_modifiers(&self) -> bool {
+        self.symbols[0].namespace() == "ModifierFlag"
+    }
+}
+
+impl Symbol {
+    pub fn new(namespace: &str, name: &str) -> Symbol {
+        Symbol { path: vec![namespace.to_string(), name.to_string()], bare: false }
+    }
+
+    /// The first segment, such as `KeyCode`
+    pub fn namespace(&self) -> &str {
+        &self.path[0]
+    }
+
+    /// The second segment, such as `A` or `RawValue`
+    pub fn name(&self) -> &str {
+        &self.path[1]
+    }
+
+    /// Any segments after the name (such as the number after `RawValue`)
+    pub fn rest(&self) -> &[String] {
+        &self.path[2..]
+    }
+}
+
+impl fmt::Display for Autogen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "--{}--", self.type_)?;
+        if !self.args.is_empty() {
+            let args = self.args.iter().map(Arg::to_string).collect::<Vec<_>>();
+            write!(f, " {}", args.join(", "))
+        } else if !self.text.is_empty() {
+            write!(f, " {}", self.text)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbols = self.symbols.iter().map(Symbol::to_string).collect::<Vec<_>>();
+        write!(f, "{}", symbols.join(" | "))
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.bare {
+            write!(f, "VK_{}", self.name())
+        } else {
+            write!(f, "{}", self.path.join("::"))
+        }
+    }
+}
+
+/// Reads tokens from an autogen or argument list, keeping track of the position for errors
+struct Tokenizer<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    fn new(source: &'a str) -> Tokenizer<'a> {
+        Tokenizer { source, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consume a string if it's next (after whitespace)
+    fn eat(&mut self, s: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Describe where we are, for error messages
+    fn error(&self, message: &str) -> Error {
+        let before = &self.source[..self.pos];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        let found = match self.rest().chars().next() {
+            Some(c) => format!("`{}`", c),
+            None => "the end".to_string(),
+        };
+        format_err!("{}, found {} at line {}, column {} of `{}`", message, found, line, column, self.source.trim())
+    }
+
+    /// `--Type--` (or `__Type__`)
+    fn header(&mut self) -> Result<String, Error> {
+        let dashes = if self.eat("--") {
+            "--"
+        } else if self.eat("__") {
+            "__"
+        } else {
+            return Err(self.error("Expected an autogen type like `--KeyToKey--`"));
+        };
+
+        let rest = self.rest();
+        match rest.find(dashes).map(|end| &rest[..end]) {
+            Some(type_) if !type_.is_empty() && type_.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+                self.pos += type_.len() + dashes.len();
+                Ok(type_.to_string())
+            }
+            _ => Err(self.error(&format!("Expected an autogen type like `{}KeyToKey{}`", dashes, dashes))),
+        }
+    }
+
+    /// Letters, digits and underscores
+    fn ident(&mut self) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    /// Comma-separated arguments, up to the end
+    fn args(&mut self) -> Result<Vec<Arg>, Error> {
+        let mut args = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.rest().is_empty() {
+                return Ok(args);
+            }
+
+            args.push(self.arg()?);
+
+            if !self.eat(",") {
+                self.skip_whitespace();
+                if self.rest().is_empty() {
+                    return Ok(args);
+                }
+                return Err(self.error("Expected `,` or `|`"));
+            }
+        }
+    }
+
+    /// Symbols joined by `|`
+    fn arg(&mut self) -> Result<Arg, Error> {
+        let mut symbols = vec![self.symbol()?];
+        while self.eat("|") {
+            symbols.push(self.symbol()?);
+        }
+        Ok(Arg { symbols })
+    }
+
+    /// `Namespace::NAME[::more]`, or a bare `VK_$mod` (old modifier syntax, read as `ModifierFlag::$mod`)
+    fn symbol(&mut self) -> Result<Symbol, Error> {
+        self.skip_whitespace();
+        let first = self.ident();
+        if first.is_empty() {
+            return Err(self.error("Expected a name like `KeyCode::A`"));
+        }
+
+        let mut path = vec![first.to_string()];
+        while self.eat("::") {
+            self.skip_whitespace();
+            let segment = self.ident();
+            if segment.is_empty() {
+                return Err(self.error(&format!("Expected a name after `{}::`", path.join("::"))));
+            }
+            path.push(segment.to_string());
+        }
+
+        let bare = path.len() == 1;
+        if bare {
+            match first.strip_prefix("VK_") {
+                Some(modifier) if !modifier.is_empty() => path = vec!["ModifierFlag".into(), modifier.into()],
+                _ => return Err(self.error(&format!("Expected `::` after `{}`", first))),
+            }
+        }
+        Ok(Symbol { path, bare })
+    }
+}
@@ -0,0 +1,31 @@
+//! Karabiner's built-in settings (from its checkbox.xml)
+//!
+//! The settings are kept in private.xml format, copied from checkbox.xml so they can be checked
+//! against it, and converted like any other item (so `--notes`, `--simple-modifications` etc.
+//! apply to them too).
+
+use failure::Error;
+
+use xml;
+
+/// A selection of the settings from checkbox.xml, under their original identifiers
+const CHECKBOX_XML: &str = include_str!("checkbox.xml");
+
+/// Database of built-in settings
+#[derive(Debug)]
+pub struct Builtins {
+    pub items: Vec<xml::Item>,
+}
+
+impl Builtins {
+    pub fn load() -> Result<Builtins, Error> {
+        Ok(Builtins {
+            items: xml::Karabiner::parse(CHECKBOX_XML.as_bytes())?.items,
+        })
+    }
+
+    /// Look up a setting by identifier
+    pub fn get(&self, identifier: &str) -> Option<&xml::Item> {
+        self.items.iter().find(|item| item.identifier.name == identifier)
+    }
+}
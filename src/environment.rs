@@ -0,0 +1,160 @@
+//! Checking which manipulators are active in a given situation
+//!
+//! This follows how Karabiner-Elements evaluates conditions: application and input source fields
+//! are regexes that can match anywhere, and variables that were never set count as 0.
+
+use failure::Error;
+use regex::Regex;
+use serde_json::Value;
+
+use json::{self, Condition};
+
+use std::collections::BTreeMap;
+
+/// The situation a key is pressed in
+#[derive(Debug, Default, Clone)]
+pub struct Environment {
+    /// Bundle identifier of the frontmost application
+    pub bundle_identifier: Option<String>,
+
+    /// Path to the frontmost application's executable
+    pub file_path: Option<String>,
+
+    /// Device the key was pressed on
+    pub device: Option<json::DeviceIdentifiers>,
+
+    /// All connected devices
+    pub connected_devices: Vec<json::DeviceIdentifiers>,
+
+    /// "ansi", "iso" or "jis"
+    pub keyboard_type: Option<String>,
+
+    /// Current input source (the actual values, not regexes)
+    pub input_source: Option<json::InputSource>,
+
+    pub variables: BTreeMap<String, Value>,
+}
+
+impl Condition {
+    /// Whether the condition holds
+    ///
+    /// Fails if a regex is invalid or the kind of condition is unknown.
+    pub fn is_met(&self, env: &Environment) -> Result<bool, Error> {
+        Ok(match *self {
+            Condition::FrontmostApplicationIf(ref apps) => applications_match(apps, env)?,
+            Condition::FrontmostApplicationUnless(ref apps) => !applications_match(apps, env)?,
+            Condition::DeviceIf(ref devices) => env.device.as_ref().is_some_and(|d| devices_match(devices, d)),
+            Condition::DeviceUnless(ref devices) => !env.device.as_ref().is_some_and(|d| devices_match(devices, d)),
+            Condition::DeviceExistsIf(ref devices) => env.connected_devices.iter().any(|d| devices_match(devices, d)),
+            Condition::DeviceExistsUnless(ref devices) => !env.connected_devices.iter().any(|d| devices_match(devices, d)),
+            Condition::KeyboardTypeIf(ref types) =>
+                env.keyboard_type.as_ref().is_some_and(|t| types.keyboard_types.contains(t)),
+            Condition::InputSourceIf(ref sources) => input_sources_match(sources, env)?,
+            Condition::InputSourceUnless(ref sources) => !input_sources_match(sources, env)?,
+            Condition::VariableIf(ref variable) => variable_matches(variable, env),
+            Condition::VariableUnless(ref variable) => !variable_matches(variable, env),
+            Condition::Other(ref other) => bail!("Can't evaluate condition {}", Value::Object(other.clone())),
+        })
+    }
+}
+
+impl json::Manipulator {
+    /// Whether all the conditions hold
+    pub fn is_active(&self, env: &Environment) -> Result<bool, Error> {
+        for condition in &self.conditions {
+            if !condition.is_met(env)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl json::Rule {
+    /// Whether the rule is switched on and any of its manipulators is active
+    pub fn is_active(&self, env: &Environment) -> Result<bool, Error> {
+        if self.other.get("enabled") == Some(&Value::Bool(false)) {
+            return Ok(false);
+        }
+        for manipulator in &self.manipulators {
+            if manipulator.is_active(env)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl json::Profile {
+    /// Complex modification rules that are active
+    pub fn active_rules(&self, env: &Environment) -> Result<Vec<&json::Rule>, Error> {
+        let mut rules = vec![];
+        for rule in &self.complex_modifications.rules {
+            if rule.is_active(env)? {
+                rules.push(rule);
+            }
+        }
+        Ok(rules)
+    }
+}
+
+/// Whether the regex matches the text (never, if there is no text)
+fn regex_matches(regex: &str, text: Option<&String>) -> Result<bool, Error> {
+    Ok(match text {
+        Some(text) => Regex::new(regex)?.is_match(text),
+        None => false,
+    })
+}
+
+fn any_matches(regexes: &[String], text: Option<&String>) -> Result<bool, Error> {
+    for regex in regexes {
+        if regex_matches(regex, text)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn applications_match(apps: &json::Applications, env: &Environment) -> Result<bool, Error> {
+    Ok(any_matches(&apps.bundle_identifiers, env.bundle_identifier.as_ref())?
+        || any_matches(&apps.file_paths, env.file_path.as_ref())?)
+}
+
+fn devices_match(devices: &json::Devices, device: &json::DeviceIdentifiers) -> bool {
+    devices.identifiers.iter().any(|m| {
+        m.vendor_id.is_none_or(|id| id == device.vendor_id)
+            && m.product_id.is_none_or(|id| id == device.product_id)
+            && m.location_id.is_none_or(|id| device.other.get("location_id") == Some(&id.into()))
+            && m.is_keyboard.is_none_or(|k| k == device.is_keyboard)
+            && m.is_pointing_device.is_none_or(|p| p == device.is_pointing_device)
+    })
+}
+
+fn input_sources_match(sources: &json::InputSources, env: &Environment) -> Result<bool, Error> {
+    let current = match env.input_source {
+        Some(ref current) => current,
+        None => return Ok(false),
+    };
+    for source in &sources.input_sources {
+        let fields = [
+            (&source.language, &current.language),
+            (&source.input_source_id, &current.input_source_id),
+            (&source.input_mode_id, &current.input_mode_id),
+        ];
+        let mut matches = true;
+        for &(regex, value) in &fields {
+            if let Some(ref regex) = *regex {
+                matches = matches && regex_matches(regex, value.as_ref())?;
+            }
+        }
+        if matches {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn variable_matches(variable: &json::Variable, env: &Environment) -> bool {
+    let zero = Value::from(0);
+    env.variables.get(&variable.name).unwrap_or(&zero) == &variable.value
+}
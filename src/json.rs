@@ -1,60 +1,536 @@
 //! Structs necessary for de/serializing Karabiner-Elements JSON format
+//!
+//! Every struct keeps the fields it doesn't know about in `other`, so that settings from newer
+//! (or older) versions of Karabiner-Elements survive being read and written back.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{self, Map, Value};
+
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+
+/// Fields that aren't modeled (passed through untouched)
+pub type Other = Map<String, Value>;
 
 /// Root element
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Karabiner {
-    /// Some settings
-    pub global: ::serde_json::Value,
+    #[serde(default)]
+    pub global: Global,
 
     /// User profiles
     pub profiles: Vec<Profile>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+impl Karabiner {
+    /// Index of the profile with this name, or with this index if it's a number
+    pub fn find_profile(&self, name_or_index: &str) -> Option<usize> {
+        self.profiles.iter().position(|p| p.name == name_or_index)
+            .or_else(|| name_or_index.parse().ok().filter(|&i| i < self.profiles.len()))
+    }
+
+    /// Add a profile, empty or as a copy of the one at `template`, and return its index
+    pub fn add_profile(&mut self, name: &str, template: Option<usize>) -> usize {
+        let mut profile = template.map_or_else(Profile::default, |i| self.profiles[i].clone());
+        profile.name = name.into();
+        profile.selected = false;
+        self.profiles.push(profile);
+        self.profiles.len() - 1
+    }
+
+    /// The profile that's selected in Karabiner-Elements
+    pub fn selected_profile_mut(&mut self) -> Option<&mut Profile> {
+        self.profiles.iter_mut().find(|p| p.selected)
+    }
+}
+
+/// Rules for the "Add rule" screen of Karabiner-Elements, from
+/// `~/.config/karabiner/assets/complex_modifications/*.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Asset {
+    pub title: String,
+    pub rules: Vec<Rule>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// Settings for all profiles
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Global {
+    pub check_for_updates_on_startup: bool,
+    pub show_in_menu_bar: bool,
+    pub show_profile_name_in_menu_bar: bool,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+impl Default for Global {
+    fn default() -> Self {
+        Global {
+            check_for_updates_on_startup: true,
+            show_in_menu_bar: true,
+            show_profile_name_in_menu_bar: false,
+            other: Other::new(),
+        }
+    }
 }
 
 /// User profile
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Profile {
     pub name: String,
     pub selected: bool,
-    pub fn_function_keys: ::serde_json::Value,
-    pub devices: ::serde_json::Value,
-    pub virtual_hid_keyboard: ::serde_json::Value,
-    pub simple_modifications: Vec<SimpleModification>,
+    pub fn_function_keys: SimpleModifications,
+    pub devices: Vec<Device>,
+    pub virtual_hid_keyboard: VirtualHidKeyboard,
+    pub simple_modifications: SimpleModifications,
 
     /// We always convert XML rulesets to complex modifications
     pub complex_modifications: ComplexModifications,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+impl Profile {
+    /// Settings for a keyboard, if there are any
+    pub fn keyboard(&self, vendor_id: u64, product_id: u64) -> Option<&Device> {
+        self.devices.iter().find(|d| d.identifiers.is_keyboard_with(vendor_id, product_id))
+    }
+
+    /// Settings for a keyboard, added with the defaults if there aren't any yet
+    pub fn keyboard_mut(&mut self, vendor_id: u64, product_id: u64) -> &mut Device {
+        let index = match self.devices.iter().position(|d| d.identifiers.is_keyboard_with(vendor_id, product_id)) {
+            Some(index) => index,
+            None => {
+                self.devices.push(Device::keyboard(vendor_id, product_id));
+                self.devices.len() - 1
+            }
+        };
+        &mut self.devices[index]
+    }
+}
+
+/// Settings for one keyboard or mouse
+///
+/// Like the other settings structs, the fields are in alphabetical order because that's how
+/// Karabiner-Elements writes them.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Device {
+    /// Turn off the laptop's keyboard while this one is connected
+    pub disable_built_in_keyboard_if_exists: bool,
+
+    /// Overrides the profile's function keys for this device
+    pub fn_function_keys: SimpleModifications,
+
+    pub identifiers: DeviceIdentifiers,
+
+    /// Leave the device alone (no modifications at all)
+    pub ignore: bool,
+
+    /// Whether caps lock turns the LED on (in newer versions)
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub manipulate_caps_lock_led: Option<bool>,
+
+    /// Overrides the profile's simple modifications for this device
+    pub simple_modifications: SimpleModifications,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+impl Device {
+    pub fn keyboard(vendor_id: u64, product_id: u64) -> Device {
+        Device {
+            identifiers: DeviceIdentifiers {
+                vendor_id,
+                product_id,
+                is_keyboard: true,
+                ..DeviceIdentifiers::default()
+            },
+            ..Device::default()
+        }
+    }
+}
+
+/// USB (or Bluetooth) IDs of a device
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceIdentifiers {
+    pub is_keyboard: bool,
+    pub is_pointing_device: bool,
+    pub product_id: u64,
+    pub vendor_id: u64,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+impl DeviceIdentifiers {
+    fn is_keyboard_with(&self, vendor_id: u64, product_id: u64) -> bool {
+        self.is_keyboard && self.vendor_id == vendor_id && self.product_id == product_id
+    }
+}
+
+/// Settings for the virtual keyboard that Karabiner-Elements sends events from
+///
+/// Which of these are used depends on the version of Karabiner-Elements, so the ones that aren't
+/// in the file are left out.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct VirtualHidKeyboard {
+    /// Delay before caps lock takes effect (0 by default)
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub caps_lock_delay_milliseconds: Option<u64>,
+
+    /// Country code reported to macOS (0 by default)
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub country_code: Option<u64>,
+
+    /// Show which sticky modifiers are on (true by default)
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub indicate_sticky_modifier_keys_state: Option<bool>,
+
+    /// Delay before a held key starts repeating
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub initial_key_repeat_milliseconds: Option<u64>,
+
+    /// Interval between repeats of a held key
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub key_repeat_milliseconds: Option<u64>,
+
+    /// Physical layout ("ansi", "iso" or "jis")
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub keyboard_type: Option<String>,
+
+    /// Physical layout, in newer versions
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub keyboard_type_v2: Option<String>,
+
+    /// Speed of the mouse keys, in percent (100 by default)
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub mouse_key_xy_scale: Option<u64>,
+
+    /// Delay before a key that is used on its own is sent (0 by default)
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub standalone_keys_delay_milliseconds: Option<u64>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+impl VirtualHidKeyboard {
+    /// Set the physical layout, in whichever field this version of Karabiner-Elements uses
+    pub fn set_keyboard_type(&mut self, keyboard_type: &str) {
+        if self.keyboard_type_v2.is_some() {
+            self.keyboard_type_v2 = Some(keyboard_type.into());
+        } else {
+            self.keyboard_type = Some(keyboard_type.into());
+        }
+    }
+}
+
+/// List of simple modifications (or `fn_function_keys`), remembering which format it was read in
+#[derive(Debug, Default, Clone)]
+pub struct SimpleModifications {
+    pub list: Vec<SimpleModification>,
+
+    /// Read from a `{"from": "to"}` object, as in the first versions of Karabiner-Elements
+    pub object: bool,
+}
+
+impl Deref for SimpleModifications {
+    type Target = Vec<SimpleModification>;
+
+    fn deref(&self) -> &Vec<SimpleModification> {
+        &self.list
+    }
+}
+
+impl DerefMut for SimpleModifications {
+    fn deref_mut(&mut self) -> &mut Vec<SimpleModification> {
+        &mut self.list
+    }
+}
+
+impl ::std::convert::From<Vec<SimpleModification>> for SimpleModifications {
+    fn from(list: Vec<SimpleModification>) -> Self {
+        SimpleModifications { list, object: false }
+    }
+}
+
+impl Serialize for SimpleModifications {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // fall back to a list if something can't be written as an object entry
+        let pairs = self.list.iter().map(SimpleModification::as_pair).collect::<Option<Vec<_>>>();
+        match pairs {
+            Some(pairs) if self.object => {
+                let map = pairs.into_iter()
+                               .map(|(from, to)| (from.to_string(), serde_json::to_value(to)))
+                               .map(|(from, to)| to.map(|to| (from, to)))
+                               .collect::<Result<Other, _>>()
+                               .map_err(::serde::ser::Error::custom)?;
+                map.serialize(serializer)
+            }
+            _ => self.list.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SimpleModifications {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Format {
+            List(Vec<SimpleModification>),
+            Object(Map<String, Value>),
+        }
+
+        Ok(match Format::deserialize(deserializer)? {
+            Format::List(list) => SimpleModifications { list, object: false },
+            Format::Object(map) => {
+                let list = map.into_iter()
+                              .map(|(from, to)| Ok(SimpleModification::new(from.as_str().into(), serde_json::from_value(to)?)))
+                              .collect::<Result<_, serde_json::Error>>()
+                              .map_err(::serde::de::Error::custom)?;
+                SimpleModifications { list, object: true }
+            }
+        })
+    }
 }
 
 /// Simple rule (single keys, no modifiers, etc)
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SimpleModification {
     pub from: KeyCode,
-    pub to: KeyCode,
+    pub to: SimpleTo,
+
+    #[serde(flatten)]
+    pub other: Other,
 }
 
-/// A key on the keyboard
-#[derive(Debug, Serialize, Deserialize)]
+impl SimpleModification {
+    pub fn new(from: KeyCodeValue, to: KeyCodeValue) -> SimpleModification {
+        SimpleModification {
+            from: KeyCode::new(from),
+            to: SimpleTo::Event(KeyCode::new(to)),
+            other: Other::new(),
+        }
+    }
+
+    /// The destination events, whichever format they're in
+    pub fn to_events(&self) -> &[KeyCode] {
+        match self.to {
+            SimpleTo::Event(ref to) => ::std::slice::from_ref(to),
+            SimpleTo::Events(ref to) => to,
+        }
+    }
+
+    /// The keys, if this is a plain key to key remapping
+    fn as_pair(&self) -> Option<(&KeyCodeValue, &KeyCodeValue)> {
+        let to = match self.to {
+            SimpleTo::Event(ref to) => to,
+            SimpleTo::Events(ref to) if to.len() == 1 => &to[0],
+            SimpleTo::Events(_) => return None,
+        };
+        if !self.other.is_empty() {
+            return None;
+        }
+        Some((self.from.as_key()?, to.as_key()?))
+    }
+}
+
+/// Destination of a simple modification
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SimpleTo {
+    /// A single event (older versions)
+    Event(KeyCode),
+
+    /// Any number of events (newer versions)
+    Events(Vec<KeyCode>),
+}
+
+/// A key on the keyboard (or another kind of event, such as `consumer_key_code`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct KeyCode {
-    pub key_code: String,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub key_code: Option<KeyCodeValue>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+impl KeyCode {
+    pub fn new(key_code: KeyCodeValue) -> KeyCode {
+        KeyCode { key_code: Some(key_code), other: Other::new() }
+    }
+
+    /// The key code, if this is nothing but a key
+    fn as_key(&self) -> Option<&KeyCodeValue> {
+        match self.key_code {
+            Some(ref key_code) if self.other.is_empty() => Some(key_code),
+            _ => None,
+        }
+    }
+}
+
+impl ::std::fmt::Display for KeyCode {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self.key_code {
+            Some(ref key_code) => write!(f, "{}", key_code),
+            None => write!(f, "{}", Value::Object(self.other.clone())),
+        }
+    }
+}
+
+/// Identifies a key, by name or (if it doesn't have one) by number
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KeyCodeValue {
+    Name(String),
+    Number(u32),
+}
+
+impl<'a> ::std::convert::From<&'a str> for KeyCodeValue {
+    fn from(s: &'a str) -> Self {
+        KeyCodeValue::Name(s.into())
+    }
+}
+
+impl ::std::fmt::Display for KeyCodeValue {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            KeyCodeValue::Name(ref s) => write!(f, "{}", s),
+            KeyCodeValue::Number(n) => write!(f, "{}", n),
+        }
+    }
 }
 
 /// A complex rule (may involve several keys, mouse buttons, modifier keys...)
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ComplexModifications {
-    /// Mysterious blob of parameters
-    pub parameters: ::serde_json::Value,
+    /// Timeouts for all rules
+    pub parameters: Parameters,
 
     /// Rulesets
     pub rules: Vec<Rule>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// Timeouts for complex modifications
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Parameters {
+    /// How close together keys must be pressed to count as simultaneous
+    #[serde(rename="basic.simultaneous_threshold_milliseconds")]
+    pub simultaneous_threshold_milliseconds: u64,
+
+    /// How long `to_delayed_action` waits for another key
+    #[serde(rename="basic.to_delayed_action_delay_milliseconds")]
+    pub to_delayed_action_delay_milliseconds: u64,
+
+    /// How long a key can be held and still count as pressed alone (`to_if_alone`)
+    #[serde(rename="basic.to_if_alone_timeout_milliseconds")]
+    pub to_if_alone_timeout_milliseconds: u64,
+
+    /// How long a key must be held for `to_if_held_down`
+    #[serde(rename="basic.to_if_held_down_threshold_milliseconds")]
+    pub to_if_held_down_threshold_milliseconds: u64,
+
+    /// Scroll speed for `mouse_motion_to_scroll`, in percent (100 by default, in newer versions)
+    #[serde(rename="mouse_motion_to_scroll.speed", skip_serializing_if="Option::is_none")]
+    pub mouse_motion_to_scroll_speed: Option<u64>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Parameters {
+            simultaneous_threshold_milliseconds: 50,
+            to_delayed_action_delay_milliseconds: 500,
+            to_if_alone_timeout_milliseconds: 1000,
+            to_if_held_down_threshold_milliseconds: 500,
+            mouse_motion_to_scroll_speed: None,
+            other: Other::new(),
+        }
+    }
 }
 
 /// Ruleset
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Rule {
     /// Short name (copied from XML)
+    #[serde(default)]
     pub description: String,
 
     /// Key replacements
     pub manipulators: Vec<Manipulator>,
+
+    /// Documentation from XML (ignored by Karabiner-Elements)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub karaconv_notes: Option<Notes>,
+
+    /// Item the rule was converted from (ignored by Karabiner-Elements)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub karaconv_source: Option<Source>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// Where a converted rule came from, so it can be found again when the item is renamed or removed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Source {
+    /// Absolute path of the XML file the item was in (left out for built-in settings)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+
+    /// Hash of the rule as it was written, to notice hand edits (see `karaconv::rule_hash`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+
+    /// Identifier of the XML item
+    pub identifier: String,
+}
+
+/// Appendix and comments of an item
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Notes {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub appendix: String,
+
+    /// Comments that aren't about a particular autogen
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub comments: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub autogens: Vec<AutogenNote>,
+}
+
+/// Comments about an autogen
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutogenNote {
+    pub autogen: String,
+    pub comments: Vec<String>,
+}
+
+impl Notes {
+    pub fn is_empty(&self) -> bool {
+        self.appendix.is_empty() && self.comments.is_empty() && self.autogens.is_empty()
+    }
 }
 
 /// Key replacement
@@ -65,14 +541,142 @@ pub struct Manipulator {
     pub type_: String,
 
     /// Origin key/button
+    #[serde(skip_serializing_if="From::is_empty", default)]
     pub from: From,
 
     /// Destination key(s)/button(s)
+    #[serde(skip_serializing_if="Vec::is_empty", default)]
     pub to: Vec<To>,
 
     /// Destination key(s)/button(s) for overlay keys
     #[serde(skip_serializing_if="Vec::is_empty", default)]
     pub to_if_alone: Vec<To>,
+
+    /// Events sent when the origin key is released
+    #[serde(skip_serializing_if="Vec::is_empty", default)]
+    pub to_after_key_up: Vec<To>,
+
+    /// Conditions under which the manipulator is active (e.g. `keyboard_type_if`)
+    #[serde(skip_serializing_if="Vec::is_empty", default)]
+    pub conditions: Vec<Condition>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+impl Manipulator {
+    /// The equivalent simple modification, if this just remaps one key to another
+    ///
+    /// That means no modifiers, conditions, flags or events other than the single `to` key.
+    pub fn as_simple_modification(&self) -> Option<SimpleModification> {
+        if self.type_ != "basic" || !self.to_if_alone.is_empty() || !self.to_after_key_up.is_empty()
+            || !self.conditions.is_empty() || !self.other.is_empty() || self.to.len() != 1 {
+            return None;
+        }
+        let from = match self.from {
+            From::Key { ref key_code, ref modifiers, ref other } if modifiers.is_empty() && other.is_empty() => key_code,
+            _ => return None,
+        };
+        let to = match self.to[0] {
+            To::Key { ref key_code, ref modifiers, ref flags, ref other }
+                if modifiers.is_empty() && flags.is_empty() && other.is_empty() => key_code,
+            _ => return None,
+        };
+        Some(SimpleModification::new(from.clone(), to.clone()))
+    }
+}
+
+/// Condition under which a manipulator is active
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag="type", rename_all="snake_case")]
+pub enum Condition {
+    FrontmostApplicationIf(Applications),
+    FrontmostApplicationUnless(Applications),
+
+    /// The event comes from one of these devices
+    DeviceIf(Devices),
+    DeviceUnless(Devices),
+
+    /// One of these devices is connected
+    DeviceExistsIf(Devices),
+    DeviceExistsUnless(Devices),
+
+    KeyboardTypeIf(KeyboardTypes),
+    InputSourceIf(InputSources),
+    InputSourceUnless(InputSources),
+
+    /// The variable has this value (unset variables are 0)
+    VariableIf(Variable),
+    VariableUnless(Variable),
+
+    /// Any other kind of condition (including its `type`)
+    #[serde(untagged)]
+    Other(Other),
+}
+
+/// Applications to match, by regexes on their bundle identifiers or paths
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Applications {
+    #[serde(skip_serializing_if="Vec::is_empty", default)]
+    pub bundle_identifiers: Vec<String>,
+
+    #[serde(skip_serializing_if="Vec::is_empty", default)]
+    pub file_paths: Vec<String>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// Devices to match
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Devices {
+    #[serde(default)]
+    pub identifiers: Vec<DeviceMatch>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// Device identifiers to match (missing ones match anything)
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceMatch {
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub vendor_id: Option<u64>,
+
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub product_id: Option<u64>,
+
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub location_id: Option<u64>,
+
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub is_keyboard: Option<bool>,
+
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub is_pointing_device: Option<bool>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// Physical layouts to match ("ansi", "iso" or "jis")
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyboardTypes {
+    #[serde(default)]
+    pub keyboard_types: Vec<String>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// Input sources to match (the fields of each are regexes)
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputSources {
+    #[serde(default)]
+    pub input_sources: Vec<InputSource>,
+
+    #[serde(flatten)]
+    pub other: Other,
 }
 
 /// Origin key/button
@@ -80,15 +684,54 @@ pub struct Manipulator {
 #[serde(untagged)]
 pub enum From {
     Key {
-        key_code: String,
+        key_code: KeyCodeValue,
+        #[serde(skip_serializing_if="FromModifiers::is_empty", default)]
+        modifiers: FromModifiers,
+        #[serde(flatten)]
+        other: Other,
+    },
+    /// Media key, such as `play_or_pause`
+    ConsumerKey {
+        consumer_key_code: String,
         #[serde(skip_serializing_if="FromModifiers::is_empty", default)]
         modifiers: FromModifiers,
+        #[serde(flatten)]
+        other: Other,
     },
     Button {
         pointing_button: String,
         #[serde(skip_serializing_if="FromModifiers::is_empty", default)]
         modifiers: FromModifiers,
+        #[serde(flatten)]
+        other: Other,
     },
+    /// Any other kind of event (such as `simultaneous` or `any`)
+    Other(Other),
+}
+
+impl Default for From {
+    fn default() -> Self {
+        From::Other(Other::new())
+    }
+}
+
+impl From {
+    /// Modifiers, if this kind of event has them
+    pub fn modifiers_mut(&mut self) -> Option<&mut FromModifiers> {
+        match *self {
+            From::Key { ref mut modifiers, .. } => Some(modifiers),
+            From::ConsumerKey { ref mut modifiers, .. } => Some(modifiers),
+            From::Button { ref mut modifiers, .. } => Some(modifiers),
+            From::Other(_) => None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match *self {
+            From::Other(ref other) => other.is_empty(),
+            _ => false,
+        }
+    }
 }
 
 /// Origin modifiers key(s)
@@ -98,40 +741,238 @@ pub struct FromModifiers {
     pub mandatory: Vec<String>,
     #[serde(skip_serializing_if="Vec::is_empty", default)]
     pub optional: Vec<String>,
+
+    #[serde(flatten)]
+    pub other: Other,
 }
 
 impl FromModifiers {
     fn is_empty(&self) -> bool {
-        self.mandatory.is_empty() && self.optional.is_empty()
+        self.mandatory.is_empty() && self.optional.is_empty() && self.other.is_empty()
     }
 }
 
-/// Destination key/button
+/// Destination event
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum To {
     Key {
-        key_code: String,
+        key_code: KeyCodeValue,
         #[serde(skip_serializing_if="Vec::is_empty", default)]
         modifiers: Vec<String>,
+        #[serde(flatten)]
+        flags: ToFlags,
+        #[serde(flatten)]
+        other: Other,
+    },
+    /// Media key, such as `play_or_pause`
+    ConsumerKey {
+        consumer_key_code: String,
+        #[serde(skip_serializing_if="Vec::is_empty", default)]
+        modifiers: Vec<String>,
+        #[serde(flatten)]
+        flags: ToFlags,
+        #[serde(flatten)]
+        other: Other,
     },
     Button {
         pointing_button: String,
         #[serde(skip_serializing_if="Vec::is_empty", default)]
         modifiers: Vec<String>,
+        #[serde(flatten)]
+        flags: ToFlags,
+        #[serde(flatten)]
+        other: Other,
+    },
+    /// Run a command with `/bin/sh`
+    ShellCommand {
+        shell_command: String,
+        #[serde(flatten)]
+        flags: ToFlags,
+        #[serde(flatten)]
+        other: Other,
+    },
+    SelectInputSource {
+        select_input_source: InputSource,
+        #[serde(flatten)]
+        flags: ToFlags,
+        #[serde(flatten)]
+        other: Other,
+    },
+    SetVariable {
+        set_variable: Variable,
+        #[serde(flatten)]
+        flags: ToFlags,
+        #[serde(flatten)]
+        other: Other,
+    },
+    SetNotificationMessage {
+        set_notification_message: NotificationMessage,
+        #[serde(flatten)]
+        flags: ToFlags,
+        #[serde(flatten)]
+        other: Other,
+    },
+    /// Move the mouse or scroll
+    MouseKey {
+        mouse_key: MouseKey,
+        #[serde(flatten)]
+        flags: ToFlags,
+        #[serde(flatten)]
+        other: Other,
+    },
+    /// Modifier name and `on`, `off` or `toggle`
+    StickyModifier {
+        sticky_modifier: BTreeMap<String, String>,
+        #[serde(flatten)]
+        flags: ToFlags,
+        #[serde(flatten)]
+        other: Other,
     },
+    /// Function name and its arguments (such as `open_application`)
+    SoftwareFunction {
+        software_function: Other,
+        #[serde(flatten)]
+        flags: ToFlags,
+        #[serde(flatten)]
+        other: Other,
+    },
+    /// Any other kind of event
+    Other(Other),
+}
+
+impl To {
+    /// Event flags, if this kind of event has them
+    pub fn flags_mut(&mut self) -> Option<&mut ToFlags> {
+        match *self {
+            To::Key { ref mut flags, .. } |
+            To::ConsumerKey { ref mut flags, .. } |
+            To::Button { ref mut flags, .. } |
+            To::ShellCommand { ref mut flags, .. } |
+            To::SelectInputSource { ref mut flags, .. } |
+            To::SetVariable { ref mut flags, .. } |
+            To::SetNotificationMessage { ref mut flags, .. } |
+            To::MouseKey { ref mut flags, .. } |
+            To::StickyModifier { ref mut flags, .. } |
+            To::SoftwareFunction { ref mut flags, .. } => Some(flags),
+            To::Other(_) => None,
+        }
+    }
 }
 
+/// Input source to switch to (the first one that matches all the given regexes)
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputSource {
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub language: Option<String>,
+
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub input_source_id: Option<String>,
+
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub input_mode_id: Option<String>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// Mouse movement or scrolling
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MouseKey {
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub x: Option<i64>,
+
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub y: Option<i64>,
+
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub vertical_wheel: Option<i64>,
+
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub horizontal_wheel: Option<i64>,
+
+    /// Multiplies the other values
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub speed_multiplier: Option<f64>,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// Variable and value (set by `set_variable`, checked by `variable_if`; used to emulate Karabiner's `vk_config` items)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Variable {
+    pub name: String,
+    pub value: Value,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// Message shown on screen (used to emulate Karabiner's status messages)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotificationMessage {
+    pub id: String,
+
+    /// Empty text clears the message
+    pub text: String,
+
+    #[serde(flatten)]
+    pub other: Other,
+}
+
+/// Optional flags on a destination event
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToFlags {
+    /// Modifier only takes effect together with another key (`VK_LAZY_*`)
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub lazy: Option<bool>,
+
+    /// Whether the key repeats while held (`Option::NOREPEAT`)
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub repeat: Option<bool>,
+
+    /// Delay before releasing the key (`VK_WAIT_*`)
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub hold_down_milliseconds: Option<u64>,
+
+    /// Cancel the events after this one (in `to_if_alone` and `to_if_held_down`)
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub halt: Option<bool>,
+}
+
+impl ToFlags {
+    pub fn is_empty(&self) -> bool {
+        *self == ToFlags::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum KeyOrButton {
-    Key(String),
+    Key(KeyCodeValue),
+    ConsumerKey(String),
     Button(String),
 }
 
-pub trait KeyOrButtonConv {
+pub trait KeyOrButtonConv: Sized {
     fn conv(key_or_button: KeyOrButton, mods: Vec<String>) -> Self;
+
+    /// The key or button and its modifiers, if this is that kind of event
+    fn key_or_button(&self) -> Option<(KeyOrButton, &[String])>;
 }
 
 impl KeyOrButtonConv for From {
+    fn key_or_button(&self) -> Option<(KeyOrButton, &[String])> {
+        match *self {
+            From::Key { ref key_code, ref modifiers, .. } => Some((KeyOrButton::Key(key_code.clone()), &modifiers.mandatory)),
+            From::ConsumerKey { ref consumer_key_code, ref modifiers, .. } =>
+                Some((KeyOrButton::ConsumerKey(consumer_key_code.clone()), &modifiers.mandatory)),
+            From::Button { ref pointing_button, ref modifiers, .. } =>
+                Some((KeyOrButton::Button(pointing_button.clone()), &modifiers.mandatory)),
+            From::Other(_) => None,
+        }
+    }
+
     fn conv(key_or_button: KeyOrButton, mods: Vec<String>) -> Self {
         match key_or_button {
             KeyOrButton::Key(s) =>
@@ -139,8 +980,19 @@ impl KeyOrButtonConv for From {
                     key_code: s,
                     modifiers: FromModifiers {
                         mandatory: mods,
-                        optional: vec![],
+                        ..FromModifiers::default()
+                    },
+                    other: Other::new(),
+                },
+
+            KeyOrButton::ConsumerKey(s) =>
+                From::ConsumerKey {
+                    consumer_key_code: s,
+                    modifiers: FromModifiers {
+                        mandatory: mods,
+                        ..FromModifiers::default()
                     },
+                    other: Other::new(),
                 },
 
             KeyOrButton::Button(s) =>
@@ -148,26 +1000,49 @@ impl KeyOrButtonConv for From {
                     pointing_button: s,
                     modifiers: FromModifiers {
                         mandatory: mods,
-                        optional: vec![],
+                        ..FromModifiers::default()
                     },
+                    other: Other::new(),
                 },
         }
     }
 }
 
 impl KeyOrButtonConv for To {
+    fn key_or_button(&self) -> Option<(KeyOrButton, &[String])> {
+        match *self {
+            To::Key { ref key_code, ref modifiers, .. } => Some((KeyOrButton::Key(key_code.clone()), modifiers)),
+            To::ConsumerKey { ref consumer_key_code, ref modifiers, .. } =>
+                Some((KeyOrButton::ConsumerKey(consumer_key_code.clone()), modifiers)),
+            To::Button { ref pointing_button, ref modifiers, .. } => Some((KeyOrButton::Button(pointing_button.clone()), modifiers)),
+            _ => None,
+        }
+    }
+
     fn conv(key_or_button: KeyOrButton, mods: Vec<String>) -> Self {
         match key_or_button {
             KeyOrButton::Key(s) =>
                 To::Key {
                     key_code: s,
                     modifiers: mods,
+                    flags: ToFlags::default(),
+                    other: Other::new(),
+                },
+
+            KeyOrButton::ConsumerKey(s) =>
+                To::ConsumerKey {
+                    consumer_key_code: s,
+                    modifiers: mods,
+                    flags: ToFlags::default(),
+                    other: Other::new(),
                 },
 
             KeyOrButton::Button(s) =>
                 To::Button {
                     pointing_button: s,
                     modifiers: mods,
+                    flags: ToFlags::default(),
+                    other: Other::new(),
                 },
         }
     }
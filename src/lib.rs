@@ -1,30 +1,51 @@
 //! Helper functions for converting from Karabiner XML format to Karabiner-Elements JSON format
 
 #[macro_use] extern crate failure;
-#[macro_use] extern crate serde;
-extern crate itertools;
-extern crate result;
+extern crate serde;
 extern crate serde_json;
+extern crate plist;
+extern crate regex;
+extern crate result;
+extern crate xml as xml_rs;
 
 pub mod xml;
 pub mod json;
+pub mod environment;
+pub mod autogen;
+pub mod prefs;
+pub mod builtin;
+pub mod seil;
+pub mod modmap;
 
 use failure::Error;
-use itertools::PeekingNext;
 use result::prelude::*;
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
 /// Convert a key code from XML to JSON format
 /// 
-/// The XML format is `KeyCode::$code` or `PointingButton::$code`.
-/// We split off the first part and look up the second in a table.
-fn conv_key(s: &str) -> Result<json::KeyOrButton, Error> {
+/// The XML format is `KeyCode::$code`, `ConsumerKeyCode::$code` or `PointingButton::$code`.
+/// We look up the code in a table.
+/// Keys can also be given as `KeyCode::RawValue::$number` (hex or decimal), which are looked up in
+/// another table. Those are macOS virtual key codes, while numbers in JSON format are HID usages,
+/// so unknown ones can't be passed through.
+fn conv_key(symbol: &autogen::Symbol) -> Result<json::KeyOrButton, Error> {
     use json::KeyOrButton::*;
 
-    let parts = s.split("::").map(str::trim).collect::<Vec<_>>();
+    Ok(match symbol.namespace() {
+        "KeyCode" if symbol.name() == "RawValue" => {
+            let raw = symbol.rest().first().ok_or_else(|| format_err!("Missing raw key code in {}", symbol))?;
+            let raw = parse_number(raw).filter(|&n| n <= u64::from(u32::MAX))
+                                       .ok_or_else(|| format_err!("Bad raw key code {}", raw))? as u32;
+
+            Key(conv_raw_key(raw).ok_or_else(|| format_err!("Unknown raw key code {:#x}", raw))?.into())
+        }
 
-    Ok(match parts[0] {
         "KeyCode" => {
-            Key(match parts[1] {
+            Key(match symbol.name() {
                 "A" => "a",
                 "B" => "b",
                 "C" => "c",
@@ -157,12 +178,37 @@ fn conv_key(s: &str) -> Result<json::KeyOrButton, Error> {
                 "CURSOR_UP"    => "up_arrow",
                 "CURSOR_DOWN"  => "down_arrow",
 
+                "JIS_EISUU"      => "lang2",
+                "JIS_KANA"       => "lang1",
+                "JIS_UNDERSCORE" => "international1",
+                "JIS_YEN"        => "international3",
+
+                "VK_NONE" => "vk_none",
+
                 otherwise => bail!("Unknown key code {}", otherwise)
             }.into())
         }
 
+        "ConsumerKeyCode" => {
+            ConsumerKey(match symbol.name() {
+                "BRIGHTNESS_DOWN"    => "display_brightness_decrement",
+                "BRIGHTNESS_UP"      => "display_brightness_increment",
+                "KEYBOARDLIGHT_LOW"  => "illumination_decrement",
+                "KEYBOARDLIGHT_HIGH" => "illumination_increment",
+                "MUSIC_PREV"         => "rewind",
+                "MUSIC_PLAY"         => "play_or_pause",
+                "MUSIC_NEXT"         => "fast_forward",
+                "VOLUME_MUTE"        => "mute",
+                "VOLUME_DOWN"        => "volume_decrement",
+                "VOLUME_UP"          => "volume_increment",
+                "EJECT"              => "eject",
+
+                otherwise => bail!("Unknown consumer key code {}", otherwise)
+            }.into())
+        }
+
         "PointingButton" => {
-            Button(match parts[1] {
+            Button(match symbol.name() {
                 "LEFT"    => "button1",
                 "RIGHT"   => "button2",
                 "MIDDLE"  => "button3",
@@ -185,36 +231,149 @@ fn conv_key(s: &str) -> Result<json::KeyOrButton, Error> {
     })
 }
 
+/// Look up a raw key code from XML format
+/// 
+/// Karabiner's raw values are macOS virtual key codes, so this is a reverse table from those numbers
+/// to the names used in JSON format.
+fn conv_raw_key(raw: u32) -> Option<&'static str> {
+    Some(match raw {
+        0x00 => "a",
+        0x01 => "s",
+        0x02 => "d",
+        0x03 => "f",
+        0x04 => "h",
+        0x05 => "g",
+        0x06 => "z",
+        0x07 => "x",
+        0x08 => "c",
+        0x09 => "v",
+        0x0a => "non_us_backslash",
+        0x0b => "b",
+        0x0c => "q",
+        0x0d => "w",
+        0x0e => "e",
+        0x0f => "r",
+        0x10 => "y",
+        0x11 => "t",
+        0x12 => "1",
+        0x13 => "2",
+        0x14 => "3",
+        0x15 => "4",
+        0x16 => "6",
+        0x17 => "5",
+        0x18 => "equal_sign",
+        0x19 => "9",
+        0x1a => "7",
+        0x1b => "hyphen",
+        0x1c => "8",
+        0x1d => "0",
+        0x1e => "close_bracket",
+        0x1f => "o",
+        0x20 => "u",
+        0x21 => "open_bracket",
+        0x22 => "i",
+        0x23 => "p",
+        0x24 => "return_or_enter",
+        0x25 => "l",
+        0x26 => "j",
+        0x27 => "quote",
+        0x28 => "k",
+        0x29 => "semicolon",
+        0x2a => "backslash",
+        0x2b => "comma",
+        0x2c => "slash",
+        0x2d => "n",
+        0x2e => "m",
+        0x2f => "period",
+        0x30 => "tab",
+        0x31 => "spacebar",
+        0x32 => "grave_accent_and_tilde",
+        0x33 => "delete_or_backspace",
+        0x35 => "escape",
+        0x36 => "right_command",
+        0x37 => "left_command",
+        0x38 => "left_shift",
+        0x39 => "caps_lock",
+        0x3a => "left_option",
+        0x3b => "left_control",
+        0x3c => "right_shift",
+        0x3d => "right_option",
+        0x3e => "right_control",
+        0x3f => "fn",
+        0x40 => "f17",
+        0x41 => "keypad_period",
+        0x43 => "keypad_asterisk",
+        0x45 => "keypad_plus",
+        0x47 => "keypad_num_lock",
+        0x48 => "volume_increment",
+        0x49 => "volume_decrement",
+        0x4a => "mute",
+        0x4b => "keypad_slash",
+        0x4c => "keypad_enter",
+        0x4e => "keypad_hyphen",
+        0x4f => "f18",
+        0x50 => "f19",
+        0x51 => "keypad_equal_sign",
+        0x52 => "keypad_0",
+        0x53 => "keypad_1",
+        0x54 => "keypad_2",
+        0x55 => "keypad_3",
+        0x56 => "keypad_4",
+        0x57 => "keypad_5",
+        0x58 => "keypad_6",
+        0x59 => "keypad_7",
+        0x5a => "f20",
+        0x5b => "keypad_8",
+        0x5c => "keypad_9",
+        0x5d => "international3",
+        0x5e => "international1",
+        0x5f => "keypad_comma",
+        0x60 => "f5",
+        0x61 => "f6",
+        0x62 => "f7",
+        0x63 => "f3",
+        0x64 => "f8",
+        0x65 => "f9",
+        0x66 => "lang2",
+        0x67 => "f11",
+        0x68 => "lang1",
+        0x69 => "f13",
+        0x6a => "f16",
+        0x6b => "f14",
+        0x6d => "f10",
+        0x6e => "application",
+        0x6f => "f12",
+        0x71 => "f15",
+        0x72 => "help",
+        0x73 => "home",
+        0x74 => "page_up",
+        0x75 => "delete_forward",
+        0x76 => "f4",
+        0x77 => "end",
+        0x78 => "f2",
+        0x79 => "page_down",
+        0x7a => "f1",
+        0x7b => "left_arrow",
+        0x7c => "right_arrow",
+        0x7d => "down_arrow",
+        0x7e => "up_arrow",
+
+        _ => return None
+    })
+}
+
 /// Convert a modifier flag from XML to JSON format
 /// 
 /// The XML format is a `|`-delimited sequence of `ModifierFlag::$code`.
 /// We look up the codes in a table.
-/// Also, there is a hack to support the old XML format which is VK_code (no `::`).
-fn conv_mod(s: &str) -> Result<Vec<String>, Error> {
-    let mut mods = s.split('|')
-                    .map(str::trim)
-                    .map(|s| s.split("::")
-                              .map(str::trim)
-                              .collect::<Vec<_>>())
-                    .collect::<Vec<_>>();
-
-    if mods.is_empty() {
-        bail!("Empty modifier");
-    } else {
-        for m in &mut mods {
-            if m[0] != "ModifierFlag" {
-                if m[0].starts_with("VK_") { // old format (fake it into the new one)
-                    *m = vec!["ModifierFlag", &m[0][3..]];
-                } else {
-                    bail!("Not a modifier: {}", &m[0]);
-                }
-            }
+fn conv_mod(arg: &autogen::Arg) -> Result<Vec<String>, Error> {
+    let mut convs = vec![];
+    for m in &arg.symbols {
+        if m.namespace() != "ModifierFlag" {
+            bail!("Not a modifier: {}", m);
         }
-    }
 
-    let mut convs = vec![];
-    for m in mods {
-        convs.push(match m[1] {
+        convs.push(match m.name() {
             "NONE"      => continue,
             "ZERO"      => "zero",
             "CAPSLOCK"  => "caps_lock",
@@ -237,21 +396,1342 @@ fn conv_mod(s: &str) -> Result<Vec<String>, Error> {
     Ok(convs)
 }
 
-/// Parse the XML format for a sequence of keys and modifiers
+/// Virtual keys which are not real events, but flags on neighbouring events
+enum VirtualKey {
+    /// `KeyCode::VK_LAZY_$mod`: a modifier key that only takes effect with another key
+    Lazy(json::KeyOrButton),
+
+    /// `KeyCode::VK_WAIT_$nMS`: hold the preceding key down for a while
+    Wait(u64),
+
+    /// `KeyCode::VK_PARTIAL_KEYDOWN` (true) or `KeyCode::VK_PARTIAL_KEYUP` (false): only press or
+    /// release the next key
+    Partial(bool),
+
+    /// `KeyCode::VK_IOHIKEYBOARD_TOGGLE_NUMLOCK`: toggle NumLock (approximated by the Clear key)
+    NumLock,
+
+    /// `KeyCode::VK_CONFIG_$action_$name`: switch a `vk_config` item on or off
+    /// 
+    /// `KeyCode::VK_MODIFIER_EXTRA$n` is also handled this way, since it holds a variable down.
+    Config(ConfigAction, String),
+}
+
+/// Variable used to emulate one of Karabiner's extra modifiers (`EXTRA1` through `EXTRA5`)
+fn extra_modifier_variable(flag: &str) -> Option<String> {
+    match flag {
+        "EXTRA1" | "EXTRA2" | "EXTRA3" | "EXTRA4" | "EXTRA5" => Some(format!("modifier_{}", flag.to_lowercase())),
+        _ => None
+    }
+}
+
+/// Recognize a virtual key from XML format
+/// 
+/// Returns `None` if the key code is not one of the virtual keys handled here.
+fn conv_virtual_key(symbol: &autogen::Symbol) -> Result<Option<VirtualKey>, Error> {
+    if symbol.namespace() != "KeyCode" {
+        return Ok(None);
+    }
+    let name = symbol.name();
+
+    if let Some(modifier) = name.strip_prefix("VK_LAZY_") {
+        return match modifier {
+            "COMMAND_L" | "COMMAND_R" | "CONTROL_L" | "CONTROL_R" | "FN" |
+            "OPTION_L" | "OPTION_R" | "SHIFT_L" | "SHIFT_R" =>
+                Ok(Some(VirtualKey::Lazy(conv_key(&autogen::Symbol::new("KeyCode", modifier))?))),
+
+            otherwise => bail!("Unknown lazy modifier {}", otherwise)
+        };
+    }
+
+    if let Some(ms) = name.strip_prefix("VK_WAIT_").and_then(|w| w.strip_suffix("MS")) {
+        return Ok(Some(VirtualKey::Wait(ms.parse()
+                                          .map_err(|_| format_err!("Bad wait time {}", name))?)));
+    }
+
+    match name {
+        "VK_PARTIAL_KEYDOWN" => return Ok(Some(VirtualKey::Partial(true))),
+        "VK_PARTIAL_KEYUP" => return Ok(Some(VirtualKey::Partial(false))),
+        "VK_IOHIKEYBOARD_TOGGLE_NUMLOCK" => return Ok(Some(VirtualKey::NumLock)),
+        _ => {}
+    }
+
+    if let Some(extra) = name.strip_prefix("VK_MODIFIER_") {
+        let variable = extra_modifier_variable(extra).ok_or_else(|| format_err!("Unknown virtual modifier {}", extra))?;
+        return Ok(Some(VirtualKey::Config(ConfigAction::SyncKeyDownUp, variable)));
+    }
+
+    if let Some(config) = name.strip_prefix("VK_CONFIG_") {
+        for &(prefix, action) in &[("TOGGLE_", ConfigAction::Toggle),
+                                   ("FORCE_ON_", ConfigAction::ForceOn),
+                                   ("FORCE_OFF_", ConfigAction::ForceOff),
+                                   ("SYNC_KEYDOWNUP_", ConfigAction::SyncKeyDownUp)] {
+            if let Some(name) = config.strip_prefix(prefix) {
+                return Ok(Some(VirtualKey::Config(action, name.into())));
+            }
+        }
+        bail!("Unknown config action {}", name);
+    }
+
+    Ok(None)
+}
+
+/// A key or button parsed from XML format, with its modifiers and event flags
+#[derive(Clone)]
+pub struct Key {
+    pub key: json::KeyOrButton,
+    pub modifiers: Vec<String>,
+    pub flags: json::ToFlags,
+}
+
+impl Key {
+    /// Name of the key or button (for messages)
+    pub fn name(&self) -> String {
+        match self.key {
+            json::KeyOrButton::Key(json::KeyCodeValue::Name(ref name)) => name.clone(),
+            json::KeyOrButton::Key(json::KeyCodeValue::Number(number)) => number.to_string(),
+            json::KeyOrButton::ConsumerKey(ref name) | json::KeyOrButton::Button(ref name) => name.clone(),
+        }
+    }
+
+    /// Name of the key or button with its modifiers, like `left_shift+a` (for messages)
+    pub fn describe(&self) -> String {
+        self.modifiers.iter().cloned().chain(Some(self.name())).collect::<Vec<_>>().join("+")
+    }
+
+    /// Name of the modifier, if this is a modifier key
+    fn modifier(&self) -> Option<&str> {
+        match self.key {
+            json::KeyOrButton::Key(json::KeyCodeValue::Name(ref name)) => match &**name {
+                "left_command" | "right_command" | "left_control" | "right_control" | "left_option" |
+                "right_option" | "left_shift" | "right_shift" | "fn" => Some(name),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    /// Convert to an origin event (flags are dropped)
+    pub fn into_from(self) -> json::From {
+        json::KeyOrButtonConv::conv(self.key, self.modifiers)
+    }
+
+    /// Convert to a destination event
+    pub fn into_to(self) -> json::To {
+        let mut to: json::To = json::KeyOrButtonConv::conv(self.key, self.modifiers);
+        if let Some(flags) = to.flags_mut() {
+            *flags = self.flags;
+        }
+        to
+    }
+}
+
+/// What a `VK_CONFIG_*` key does to its `vk_config` item
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigAction {
+    /// Switch on if off, and vice versa
+    Toggle,
+    /// Switch on
+    ForceOn,
+    /// Switch off
+    ForceOff,
+    /// Switch on while the key is held down
+    SyncKeyDownUp,
+}
+
+/// An element of a key sequence parsed from XML format
+#[derive(Clone)]
+pub enum Event {
+    /// A real key or button
+    Key(Key),
+
+    /// Only the key-down half of a key (`VK_PARTIAL_KEYDOWN`)
+    KeyDown(Key),
+
+    /// Only the key-up half of a key (`VK_PARTIAL_KEYUP`)
+    KeyUp(Key),
+
+    /// A change to the state of a `vk_config` item (by the name used in `VK_CONFIG_*` key codes) or
+    /// an extra modifier (by variable name)
+    Config(ConfigAction, String),
+}
+
+impl Event {
+    /// Unwrap a real key or button (`VK_CONFIG_*` keys can't be pressed)
+    pub fn into_key(self) -> Result<Key, Error> {
+        match self {
+            Event::Key(key) => Ok(key),
+            Event::KeyDown(..) | Event::KeyUp(..) => bail!("Partial keys can only be used as destinations"),
+            Event::Config(..) => bail!("Config keys can only be used as destinations"),
+        }
+    }
+}
+
+/// Keyboard types known to Karabiner-Elements
+pub const KEYBOARD_TYPES: &[&str] = &["ansi", "iso", "jis"];
+
+/// Convert a keyboard type from XML to JSON format
+/// 
+/// The XML format is `KeyboardType::$type`, one of the Mac models from Karabiner's
+/// keyboardtype.xml. Karabiner-Elements only cares about the physical layout of each model.
+pub fn conv_keyboard_type(symbol: &autogen::Symbol) -> Result<&'static str, Error> {
+    if symbol.namespace() != "KeyboardType" || !symbol.rest().is_empty() {
+        bail!("Not a keyboard type: {}", symbol);
+    }
+
+    Ok(match symbol.name() {
+        "MACBOOK" => "ansi",
+        "MACBOOK_COREDUO" => "ansi",
+        "POWERBOOK" => "ansi",
+        "POWERBOOK_G4" => "ansi",
+        "POWERBOOK_G4_TI" => "ansi",
+        "JIS_MACBOOK" => "jis",
+        "JIS_MACBOOK_2008" => "jis",
+        "JIS_PC_KEYBOARD" => "jis",
+        "JIS_APPLE_USB_KEYBOARD" => "jis",
+
+        otherwise => bail!("Unknown keyboard type {}", otherwise)
+    })
+}
+
+/// Convert `<keyboardtype_only>` and `<keyboardtype_not>` filters to a list of keyboard types
+/// 
+/// The XML format is a comma-delimited list of `KeyboardType::$type`. Since JSON format only has
+/// `keyboard_type_if`, exclusions are converted by listing all the other layouts.
+pub fn collect_keyboard_types(only: Option<&str>, not: Option<&str>) -> Result<Vec<String>, Error> {
+    fn collect(s: &str) -> Result<Vec<&'static str>, Error> {
+        autogen::parse_args(s)?.iter().flat_map(|arg| &arg.symbols).map(conv_keyboard_type).collect()
+    }
+
+    let mut types = match only {
+        Some(only) => collect(only)?,
+        None => KEYBOARD_TYPES.to_vec(),
+    };
+    if let Some(not) = not {
+        let not = collect(not)?;
+        types.retain(|t| !not.contains(t));
+    }
+    types.sort();
+    types.dedup();
+
+    if types.is_empty() {
+        bail!("Keyboard type filters exclude every keyboard");
+    }
+    Ok(types.into_iter().map(String::from).collect())
+}
+
+/// Convert the arguments of an autogen to a sequence of keys and modifiers
 /// 
 /// The syntax is comma-delimited, with alternating keys and (optional) modifiers:
 /// `key1[, mod1], key2[, mod2] ...`
-pub fn collect_keys(s: &str) -> Result<Vec<(json::KeyOrButton, Vec<String>)>, Error> {
-    let mut parts = s.split(',').map(str::trim).peekable();
-    let mut keys = vec![];
+///
+/// Some virtual keys and options are folded into flags on the keys:
+/// - `KeyCode::VK_LAZY_$mod` becomes the modifier key with `lazy` set
+/// - `KeyCode::VK_WAIT_$nMS` sets `hold_down_milliseconds` on the preceding key
+/// - `Option::NOREPEAT` sets `repeat` to false on every key
+///
+/// `KeyCode::VK_CONFIG_*` keys are returned as `Event::Config`, and keys after
+/// `KeyCode::VK_PARTIAL_KEYDOWN`/`KeyCode::VK_PARTIAL_KEYUP` as `Event::KeyDown`/`Event::KeyUp`.
+pub fn collect_keys(args: &[autogen::Arg], losses: &mut Vec<String>) -> Result<Vec<Event>, Error> {
+    let mut keys: Vec<Event> = vec![];
+    let mut repeat = None;
+    let mut partial = None;
+
+    for section in autogen::sections(args)? {
+        if let Some(option) = section.option {
+            match option.name() {
+                "NOREPEAT" => repeat = Some(false),
+                _ => bail!("Unsupported option {}", option)
+            }
+        }
+
+        for arg in section.keys {
+            let part = arg.key;
+            let mut flags = json::ToFlags::default();
+            let thekey = match conv_virtual_key(part)? {
+                Some(VirtualKey::Lazy(key)) => {
+                    flags.lazy = Some(true);
+                    key
+                }
+
+                Some(VirtualKey::NumLock) => {
+                    losses.push("NumLock can't be toggled, so the keypad Clear key is sent instead".into());
+                    json::KeyOrButton::Key("keypad_num_lock".into())
+                }
 
-    while let Some(thekey) = parts.next().map(conv_key).invert()? {
-        let themod = parts.peeking_next(|p| p.starts_with("Mod") || p.starts_with("VK"))
-                          .map(|p| conv_mod(p)).invert()?
-                          .unwrap_or(vec![]);
-        keys.push((thekey, themod));
+                Some(_) if arg.modifiers.is_some() => bail!("{} can't have modifiers", part),
+
+                Some(VirtualKey::Wait(ms)) => {
+                    match keys.last_mut() {
+                        Some(&mut Event::Key(ref mut prev)) =>
+                            prev.flags.hold_down_milliseconds = Some(prev.flags.hold_down_milliseconds.unwrap_or(0) + ms),
+                        _ => bail!("{} must follow a key", part)
+                    }
+                    continue;
+                }
+
+                Some(VirtualKey::Config(action, name)) => {
+                    keys.push(Event::Config(action, name));
+                    continue;
+                }
+
+                Some(VirtualKey::Partial(down)) => {
+                    partial = Some(down);
+                    continue;
+                }
+
+                None => conv_key(part)?
+            };
+            let themod = arg.modifiers.map(conv_mod).invert()?.unwrap_or_default();
+            let key = Key { key: thekey, modifiers: themod, flags };
+            keys.push(match partial.take() {
+                Some(true) => Event::KeyDown(key),
+                Some(false) => Event::KeyUp(key),
+                None => Event::Key(key),
+            });
+        }
+    }
+    if partial.is_some() {
+        bail!("Partial key without a key: {}", args.iter().map(autogen::Arg::to_string).collect::<Vec<_>>().join(", "));
+    }
+
+    if repeat.is_some() {
+        for key in &mut keys {
+            if let Event::Key(ref mut key) = *key {
+                key.flags.repeat = repeat;
+            }
+        }
     }
     
     Ok(keys)
 }
 
+/// Karabiner-Elements version (some features are missing from older versions)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl Version {
+    /// First version with `set_notification_message`
+    pub const NOTIFICATION_MESSAGE: Version = Version(13, 1, 0);
+}
+
+impl FromStr for Version {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let parts = s.trim().split('.')
+                     .map(|p| p.parse().map_err(|_| format_err!("Bad version {}", s)))
+                     .collect::<Result<Vec<u32>, Error>>()?;
+        match parts[..] {
+            [major] => Ok(Version(major, 0, 0)),
+            [major, minor] => Ok(Version(major, minor, 0)),
+            [major, minor, patch] => Ok(Version(major, minor, patch)),
+            _ => bail!("Bad version {}", s)
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// A `vk_config` item, which is emulated with a variable
+pub struct VkConfig {
+    /// Item identifier (used as the variable name)
+    pub identifier: String,
+
+    /// Shown while the item is switched on (`--ShowStatusMessage--`)
+    pub status_message: Option<String>,
+}
+
+/// All the `vk_config` items, by the name used in `VK_CONFIG_*` key codes
+#[derive(Default)]
+pub struct VkConfigs(HashMap<String, VkConfig>);
+
+impl VkConfigs {
+    /// Register a `vk_config` item
+    pub fn insert(&mut self, identifier: &str, status_message: Option<String>) {
+        self.0.insert(identifier.replace('.', "_"),
+                      VkConfig {
+                          identifier: identifier.into(),
+                          status_message,
+                      });
+    }
+
+    /// Forget all status messages (for Karabiner-Elements versions that can't show them)
+    pub fn clear_status_messages(&mut self) {
+        for config in self.0.values_mut() {
+            config.status_message = None;
+        }
+    }
+
+    /// Whether any item has a status message
+    pub fn has_status_messages(&self) -> bool {
+        self.0.values().any(|c| c.status_message.is_some())
+    }
+
+    /// Variable name for a `VK_CONFIG_*` key code (items not in private.xml keep the key code's name)
+    fn variable<'a>(&'a self, name: &'a str) -> &'a str {
+        self.0.get(name).map_or(name, |c| &c.identifier)
+    }
+
+    /// Events that switch an item on or off
+    fn switch(&self, name: &str, on: bool) -> Vec<json::To> {
+        let variable = self.variable(name);
+        let mut events = vec![json::To::SetVariable {
+            set_variable: json::Variable {
+                name: variable.into(),
+                value: (on as i64).into(),
+                other: json::Other::new(),
+            },
+            flags: json::ToFlags::default(),
+            other: json::Other::new(),
+        }];
+        if let Some(message) = self.0.get(name).and_then(|c| c.status_message.as_ref()) {
+            events.push(json::To::SetNotificationMessage {
+                set_notification_message: json::NotificationMessage {
+                    id: variable.into(),
+                    text: if on { message.clone() } else { String::new() },
+                    other: json::Other::new(),
+                },
+                flags: json::ToFlags::default(),
+                other: json::Other::new(),
+            });
+        }
+        events
+    }
+
+    /// Condition that an item (or the variable for an extra modifier) is switched on or off
+    pub fn condition(identifier: &str, on: bool) -> json::Condition {
+        let variable = json::Variable {
+            name: identifier.into(),
+            value: 1.into(),
+            other: json::Other::new(),
+        };
+        if on {
+            json::Condition::VariableIf(variable)
+        } else {
+            json::Condition::VariableUnless(variable)
+        }
+    }
+}
+
+/// Fold partial key presses into whole ones
+/// 
+/// Karabiner could press a key in one place and release it in another, but Karabiner-Elements
+/// presses and releases each destination key (except the last one, which is held down along with
+/// the origin key). So a modifier that is held down around other keys is added to their modifiers,
+/// another key held around them is pressed before them, and a key that is pressed but never
+/// released is moved to the end. A release without a press is left as `Event::KeyUp`, to be sent
+/// when the origin key is released.
+fn fold_partial_keys(events: Vec<Event>, losses: &mut Vec<String>) -> Vec<Event> {
+    // keys currently held down, and whether they were already sent
+    let mut held: Vec<(Key, bool)> = vec![];
+    let mut folded = vec![];
+
+    for event in events {
+        match event {
+            Event::KeyDown(key) => held.push((key, false)),
+
+            Event::KeyUp(key) => {
+                match held.iter().position(|h| h.0.key == key.key) {
+                    Some(i) => {
+                        let (key, used) = held.remove(i);
+                        if !used {
+                            folded.push(Event::Key(key));
+                        }
+                    }
+
+                    None => {
+                        losses.push(format!("{} can't be released without being pressed in the same autogen, so it is pressed and released when the origin key is released",
+                                            key.name()));
+                        folded.push(Event::KeyUp(key));
+                    }
+                }
+            }
+
+            Event::Key(mut key) => {
+                for &mut (ref h, ref mut sent) in &mut held {
+                    match h.modifier() {
+                        Some(modifier) => {
+                            *sent = true;
+                            if !key.modifiers.iter().any(|m| m == modifier) {
+                                key.modifiers.push(modifier.into());
+                            }
+                        }
+
+                        None if !*sent => {
+                            *sent = true;
+                            losses.push(format!("{} can't be held down while {} is pressed, so it is pressed separately before it",
+                                                h.name(), key.name()));
+                            folded.push(Event::Key(h.clone()));
+                        }
+
+                        None => {}
+                    }
+                }
+                folded.push(Event::Key(key));
+            }
+
+            other => folded.push(other),
+        }
+    }
+
+    for (key, sent) in held {
+        // other keys were already pressed before the keys they were held around
+        if sent && key.modifier().is_none() {
+            continue;
+        }
+        losses.push(format!("{} is released along with the origin key, rather than by a later autogen", key.name()));
+        folded.push(Event::Key(key));
+    }
+
+    folded
+}
+
+/// Convert a destination key sequence to JSON format
+/// 
+/// `toggle` decides which way any `VK_CONFIG_TOGGLE_*` keys go. Keys which switch an item on only
+/// while held down, and releases left over by `fold_partial_keys`, add events to `after_key_up`.
+fn conv_to(events: Vec<Event>, configs: &VkConfigs, toggle: bool, after_key_up: &mut Vec<json::To>) -> Vec<json::To> {
+    let mut to = vec![];
+    for event in events {
+        match event {
+            Event::Key(key) | Event::KeyDown(key) => to.push(key.into_to()),
+            Event::KeyUp(key) => after_key_up.push(key.into_to()),
+            Event::Config(ConfigAction::Toggle, name) => to.extend(configs.switch(&name, toggle)),
+            Event::Config(ConfigAction::ForceOn, name) => to.extend(configs.switch(&name, true)),
+            Event::Config(ConfigAction::ForceOff, name) => to.extend(configs.switch(&name, false)),
+            Event::Config(ConfigAction::SyncKeyDownUp, name) => {
+                to.extend(configs.switch(&name, true));
+                after_key_up.extend(configs.switch(&name, false));
+            }
+        }
+    }
+    to
+}
+
+/// Build the manipulators for an autogen
+/// 
+/// Normally this is just one manipulator, but toggling a `vk_config` item needs one for each
+/// direction, conditioned on the current state.
+pub fn build_manipulators(from: json::From, to: Vec<Event>, to_if_alone: Vec<Event>,
+                          conditions: &[json::Condition], configs: &VkConfigs,
+                          losses: &mut Vec<String>) -> Result<Vec<json::Manipulator>, Error> {
+    let to = fold_partial_keys(to, losses);
+    let to_if_alone = fold_partial_keys(to_if_alone, losses);
+
+    let mut toggles = to.iter().chain(&to_if_alone).filter_map(|e| match *e {
+        Event::Config(ConfigAction::Toggle, ref name) => Some(name.clone()),
+        _ => None,
+    }).collect::<Vec<_>>();
+    toggles.sort();
+    toggles.dedup();
+    if toggles.len() > 1 {
+        bail!("Can't toggle more than one config at once: {}", toggles.join(", "));
+    }
+
+    let build = |toggle: bool, extra_condition: Option<json::Condition>| {
+        let mut to_after_key_up = vec![];
+        let mut manipulator = json::Manipulator {
+            type_: "basic".into(),
+            from: from.clone(),
+            to: conv_to(to.clone(), configs, toggle, &mut to_after_key_up),
+            to_if_alone: conv_to(to_if_alone.clone(), configs, toggle, &mut to_after_key_up),
+            to_after_key_up: vec![],
+            conditions: conditions.to_vec(),
+            other: json::Other::new(),
+        };
+        manipulator.to_after_key_up = to_after_key_up;
+        manipulator.conditions.extend(extra_condition);
+        manipulator
+    };
+
+    Ok(match toggles.pop() {
+        Some(name) => {
+            let variable = configs.variable(&name);
+            vec![build(false, Some(VkConfigs::condition(variable, true))),
+                 build(true, Some(VkConfigs::condition(variable, false)))]
+        }
+
+        None => vec![build(false, None)]
+    })
+}
+
+/// Application definitions, as `bundle_identifiers` regexes by name
+pub struct AppDefs(HashMap<String, Vec<String>>);
+
+impl AppDefs {
+    /// Built-in definitions (a few popular ones from Karabiner's appdef.xml), plus those from private.xml
+    pub fn new(appdefs: &[xml::AppDef]) -> AppDefs {
+        let mut map = HashMap::new();
+        for &(name, ids) in &[("TERMINAL", &["com.apple.Terminal", "com.googlecode.iterm2"][..]),
+                              ("FINDER", &["com.apple.finder"]),
+                              ("SAFARI", &["com.apple.Safari"]),
+                              ("GOOGLE_CHROME", &["com.google.Chrome"]),
+                              ("FIREFOX", &["org.mozilla.firefox"]),
+                              ("XCODE", &["com.apple.dt.Xcode"]),
+                              ("KEYNOTE", &["com.apple.iWork.Keynote"]),
+                              ("EMACS", &["org.gnu.Emacs", "org.gnu.AquamacsEmacs"]),
+                              ("VI", &["org.vim.MacVim"])] {
+            map.insert(name.to_string(),
+                       ids.iter().map(|id| format!("^{}$", regex::escape(id))).collect());
+        }
+
+        for appdef in appdefs {
+            let regexes = appdef.equal.iter().map(|id| format!("^{}$", regex::escape(id)))
+                                .chain(appdef.prefix.iter().map(|id| format!("^{}", regex::escape(id))))
+                                .chain(appdef.suffix.iter().map(|id| format!("{}$", regex::escape(id))))
+                                .collect();
+            map.insert(appdef.appname.clone(), regexes);
+        }
+
+        AppDefs(map)
+    }
+
+    /// Look up a comma-delimited list of application names
+    pub fn bundle_identifiers(&self, names: &str) -> Result<Vec<String>, Error> {
+        let mut ids = vec![];
+        for name in names.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            ids.extend(self.0.get(name)
+                             .ok_or_else(|| format_err!("Unknown application {} (add an <appdef> for it)", name))?
+                             .iter().cloned());
+        }
+        Ok(ids)
+    }
+}
+
+/// Parse a number in hex (`0x...`) or decimal
+pub fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Device vendor and product definitions, as IDs by name
+pub struct DeviceDefs {
+    vendors: HashMap<String, u64>,
+    products: HashMap<String, u64>,
+}
+
+impl DeviceDefs {
+    /// Built-in definitions (Apple, from Karabiner's devicevendordef.xml), plus those from private.xml
+    pub fn new(devicedefs: &[xml::DeviceDef]) -> DeviceDefs {
+        let mut defs = DeviceDefs { vendors: HashMap::new(), products: HashMap::new() };
+        defs.vendors.insert("APPLE_COMPUTER".into(), 0x05ac);
+        for def in devicedefs {
+            let map = if def.product { &mut defs.products } else { &mut defs.vendors };
+            map.insert(def.name.clone(), def.id);
+        }
+        defs
+    }
+
+    /// Look up a list of devices, such as `DeviceVendor::APPLE_COMPUTER, DeviceProduct::ANY`
+    ///
+    /// Each vendor starts a new device, and may be followed by a product and a location.
+    pub fn devices(&self, value: &str) -> Result<Vec<json::DeviceMatch>, Error> {
+        let mut devices: Vec<json::DeviceMatch> = vec![];
+        for arg in autogen::parse_args(value)? {
+            if arg.symbols.len() != 1 {
+                bail!("Bad device {}", value);
+            }
+            let symbol = &arg.symbols[0];
+            let id = match symbol.name() {
+                "ANY" => None,
+                "RawValue" => Some(symbol.rest().first().and_then(|n| parse_number(n))
+                                         .ok_or_else(|| format_err!("Bad device ID {}", symbol))?),
+                name => {
+                    let (map, def) = match symbol.namespace() {
+                        "DeviceVendor" => (&self.vendors, "devicevendordef"),
+                        "DeviceProduct" => (&self.products, "deviceproductdef"),
+                        _ => bail!("Unknown device {} (only raw locations are supported)", symbol),
+                    };
+                    Some(*map.get(name).ok_or_else(|| format_err!("Unknown device {} (add a <{}> for it)", symbol, def))?)
+                }
+            };
+
+            if symbol.namespace() == "DeviceVendor" {
+                devices.push(json::DeviceMatch { vendor_id: id, ..json::DeviceMatch::default() });
+                continue;
+            }
+            let device = devices.last_mut().ok_or_else(|| format_err!("{} without a vendor in {}", symbol, value))?;
+            match symbol.namespace() {
+                "DeviceProduct" => device.product_id = id,
+                "DeviceLocation" => device.location_id = id,
+                _ => bail!("Unknown device {}", symbol),
+            }
+        }
+        Ok(devices)
+    }
+}
+
+/// Input source definitions, as regexes by name
+pub struct InputSourceDefs(HashMap<String, json::InputSource>);
+
+impl InputSourceDefs {
+    /// Built-in definitions (some languages from Karabiner's inputsourcedef.xml), plus those from private.xml
+    pub fn new(inputsourcedefs: &[xml::InputSourceDef]) -> InputSourceDefs {
+        let exactly = |s: &str| format!("^{}$", regex::escape(s));
+        let mut map = HashMap::new();
+        for &(name, language) in &[("ENGLISH", "en"), ("FRENCH", "fr"), ("GERMAN", "de"), ("SWEDISH", "sv"),
+                                   ("RUSSIAN", "ru"), ("JAPANESE", "ja"), ("KOREAN", "ko"),
+                                   ("CHINESE_TRADITIONAL", "zh-Hant"), ("CHINESE_SIMPLIFIED", "zh-Hans")] {
+            map.insert(name.to_string(), json::InputSource { language: Some(exactly(language)), ..json::InputSource::default() });
+        }
+
+        for def in inputsourcedefs {
+            let id = |equal: &Option<String>, prefix: &Option<String>| {
+                equal.as_ref().map(|id| exactly(id)).or_else(|| prefix.as_ref().map(|id| format!("^{}", regex::escape(id))))
+            };
+            map.insert(def.name.clone(), json::InputSource {
+                language: def.languagecode.as_ref().map(|l| exactly(l)),
+                input_source_id: id(&def.inputsourceid_equal, &def.inputsourceid_prefix),
+                input_mode_id: id(&def.inputmodeid_equal, &def.inputmodeid_prefix),
+                ..json::InputSource::default()
+            });
+        }
+
+        InputSourceDefs(map)
+    }
+
+    /// Look up a comma-delimited list of input source names
+    pub fn input_sources(&self, names: &str) -> Result<Vec<json::InputSource>, Error> {
+        names.split(',').map(str::trim).filter(|s| !s.is_empty())
+             .map(|name| self.0.get(name).cloned()
+                             .ok_or_else(|| format_err!("Unknown input source {} (add an <inputsourcedef> for it)", name)))
+             .collect()
+    }
+}
+
+/// Information from the whole XML file that is needed to convert each item
+pub struct Context {
+    pub configs: VkConfigs,
+    pub appdefs: AppDefs,
+    pub devicedefs: DeviceDefs,
+    pub inputsourcedefs: InputSourceDefs,
+}
+
+impl Context {
+    pub fn new(karabiner: &xml::Karabiner) -> Result<Context, Error> {
+        let mut configs = VkConfigs::default();
+        for item in &karabiner.items {
+            if item.identifier.is_vk_config() {
+                let mut status_message = None;
+                for autogen in item.block.autogens() {
+                    let autogen = autogen::Autogen::parse(autogen)?;
+                    if autogen.type_ == "ShowStatusMessage" {
+                        status_message = Some(autogen.text);
+                    }
+                }
+                configs.insert(&item.identifier.name, status_message);
+            }
+        }
+
+        Ok(Context {
+            configs,
+            appdefs: AppDefs::new(&karabiner.appdefs),
+            devicedefs: DeviceDefs::new(&karabiner.devicedefs),
+            inputsourcedefs: InputSourceDefs::new(&karabiner.inputsourcedefs),
+        })
+    }
+}
+
+/// Result of converting an item
+pub struct Converted {
+    /// Rule containing all the manipulators (may be empty)
+    pub rule: json::Rule,
+
+    /// Keyboard type for the profile (`--SetKeyboardType--`)
+    pub keyboard_type: Option<&'static str>,
+
+    /// Ways in which the rule doesn't do exactly what the item did (filters which Karabiner-Elements
+    /// can't express, etc)
+    pub losses: Vec<String>,
+
+    /// Appendix and comments of the item
+    pub notes: json::Notes,
+}
+
+impl Converted {
+    /// Mention the fidelity losses in the rule description
+    pub fn describe_losses(&mut self) {
+        if !self.losses.is_empty() {
+            self.rule.description = format!("{}{}{}]", self.rule.description, LOSSES_MARKER, self.losses.join("; "));
+        }
+    }
+
+    /// The rule as simple modifications, if all it does is remap single keys to other keys
+    pub fn simple_modifications(&self) -> Option<Vec<json::SimpleModification>> {
+        let modifications = self.rule.manipulators.iter()
+                                .map(json::Manipulator::as_simple_modification)
+                                .collect::<Option<Vec<_>>>()?;
+        let repeated = modifications.iter().enumerate().any(|(i, m)| modifications[..i].iter().any(|p| p.from == m.from));
+        if modifications.is_empty() || repeated {
+            return None; // the same key remapped twice would need the rule's ordering
+        }
+        Some(modifications)
+    }
+
+    /// Put the notes in the rule description
+    pub fn describe_notes(&mut self) {
+        let notes = &self.notes;
+        let mut parts = vec![];
+        if !notes.appendix.is_empty() {
+            parts.push(notes.appendix.clone());
+        }
+        parts.extend(notes.comments.iter().cloned());
+        for autogen in &notes.autogens {
+            parts.push(format!("{}: {}", autogen.autogen, autogen.comments.join("; ")));
+        }
+
+        if !parts.is_empty() {
+            self.rule.description = format!("{}{}{}]", self.rule.description, NOTES_MARKER, parts.join("; ").replace('\n', " "));
+        }
+    }
+
+    /// Put the notes in the `karaconv_notes` field of the rule
+    pub fn attach_notes(&mut self) {
+        if !self.notes.is_empty() {
+            self.rule.karaconv_notes = Some(self.notes.clone());
+        }
+    }
+
+    /// Record which file the item came from, and the hash of the finished rule
+    pub fn record_source(&mut self, file: Option<String>) -> Result<(), Error> {
+        let hash = rule_hash(&self.rule)?;
+        if let Some(ref mut source) = self.rule.karaconv_source {
+            source.file = file;
+            source.hash = Some(hash);
+        }
+        Ok(())
+    }
+}
+
+/// Separates the item name from fidelity losses in a rule description
+const LOSSES_MARKER: &str = " [approximated: ";
+
+/// Separates the item name from notes in a rule description
+const NOTES_MARKER: &str = " [notes: ";
+
+/// Whether a rule was converted from the item with this name (the description may mention fidelity
+/// losses or notes after the name)
+pub fn is_rule_for(rule: &json::Rule, name: &str) -> bool {
+    rule.description == name
+        || rule.description.strip_prefix(name)
+                           .is_some_and(|rest| rest.starts_with(LOSSES_MARKER) || rest.starts_with(NOTES_MARKER))
+}
+
+/// Whether `existing` is the rule for the item called `name` that `rule` was converted from
+///
+/// Rules that both know their source item are matched on its identifier, so renaming an item
+/// doesn't leave its old rule behind. Others (written by hand, or by an older karaconv) are matched
+/// on the name (see `is_rule_for`).
+pub fn is_same_rule(existing: &json::Rule, name: &str, rule: &json::Rule) -> bool {
+    match (&existing.karaconv_source, &rule.karaconv_source) {
+        (Some(theirs), Some(ours)) => theirs.identifier == ours.identifier,
+        _ => is_rule_for(existing, name),
+    }
+}
+
+/// What to do with a rule that's already there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Merge {
+    /// Overwrite its description and manipulators, unless it doesn't know its source item (so it
+    /// may have been written by hand)
+    Replace,
+
+    /// Overwrite it even if it doesn't know its source item
+    ReplaceUntracked,
+
+    /// Leave it alone
+    SkipExisting,
+
+    /// Add the manipulators it doesn't have yet
+    AppendManipulators,
+
+    /// Stop with an error
+    FailOnConflict,
+}
+
+impl FromStr for Merge {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "replace" => Ok(Merge::Replace),
+            "replace-untracked" => Ok(Merge::ReplaceUntracked),
+            "skip-existing" => Ok(Merge::SkipExisting),
+            "append-manipulators" => Ok(Merge::AppendManipulators),
+            "fail-on-conflict" => Ok(Merge::FailOnConflict),
+            _ => bail!("Unknown merge strategy {} (expected replace, replace-untracked, skip-existing, append-manipulators or fail-on-conflict)", s),
+        }
+    }
+}
+
+/// What `add_rule` did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Merged {
+    Added,
+    Replaced,
+
+    /// Replaced a rule that didn't know its source item, so it may have been written by hand
+    /// (its description is given)
+    ReplacedUntracked(String),
+
+    Skipped,
+
+    /// Left a rule alone because it didn't know its source item (its description is given)
+    SkippedUntracked(String),
+
+    /// Number of manipulators added
+    Appended(usize),
+}
+
+impl fmt::Display for Merged {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Merged::Added => write!(f, "adding new rule"),
+            Merged::Replaced => write!(f, "replacing existing rule"),
+            Merged::ReplacedUntracked(ref description) =>
+                write!(f, "replacing rule \"{}\" (not made by this version of karaconv, so it may have been written by hand)", description),
+            Merged::Skipped => write!(f, "skipping, the rule already exists"),
+            Merged::SkippedUntracked(ref description) =>
+                write!(f, "skipping, rule \"{}\" was not made by this version of karaconv and may have been written by hand (use --merge replace-untracked to replace it)", description),
+            Merged::Appended(0) => write!(f, "rule already has all the manipulators"),
+            Merged::Appended(n) => write!(f, "appending {} manipulator(s) to existing rule", n),
+        }
+    }
+}
+
+/// Add a rule, merging it into the existing rule for the same item (see `is_same_rule`)
+///
+/// Fields of the existing rule that we don't set (such as `enabled`) are kept. Fails on an
+/// existing rule with `Merge::FailOnConflict`.
+pub fn add_rule(rules: &mut Vec<json::Rule>, name: &str, rule: json::Rule, merge: Merge) -> Result<Merged, Error> {
+    let existing = match rules.iter_mut().find(|r| is_same_rule(r, name, &rule)) {
+        Some(existing) => existing,
+        None => {
+            rules.push(rule);
+            return Ok(Merged::Added);
+        }
+    };
+
+    let untracked = existing.karaconv_source.is_none() && rule.karaconv_source.is_some();
+    Ok(match merge {
+        Merge::Replace if untracked => Merged::SkippedUntracked(existing.description.clone()),
+        Merge::Replace | Merge::ReplaceUntracked => {
+            let old_description = std::mem::replace(&mut existing.description, rule.description);
+            existing.manipulators = rule.manipulators;
+            existing.karaconv_notes = rule.karaconv_notes;
+            existing.karaconv_source = rule.karaconv_source;
+            if untracked {
+                Merged::ReplacedUntracked(old_description)
+            } else {
+                Merged::Replaced
+            }
+        }
+        Merge::SkipExisting => Merged::Skipped,
+        Merge::AppendManipulators => {
+            let have = existing.manipulators.iter().map(serde_json::to_value).collect::<Result<Vec<_>, _>>()?;
+            let mut appended = 0;
+            for manipulator in rule.manipulators {
+                if !have.contains(&serde_json::to_value(&manipulator)?) {
+                    existing.manipulators.push(manipulator);
+                    appended += 1;
+                }
+            }
+
+            // the rule is now what karaconv wrote, so that `--prune` doesn't take it for hand-edited
+            if appended > 0 && existing.karaconv_source.is_some() {
+                if let Some(mut source) = rule.karaconv_source {
+                    source.hash = Some(rule_hash(existing)?);
+                    existing.karaconv_source = Some(source);
+                }
+            }
+            Merged::Appended(appended)
+        }
+        Merge::FailOnConflict => bail!("There already is a rule for {} (\"{}\")", name, existing.description),
+    })
+}
+
+/// Remove the existing rule for an item that is converted to simple modifications instead
+///
+/// The rule is found as in `add_rule`, and only removed if `merge` would replace it. Otherwise it
+/// returns what `add_rule` did with the rule, and the simple modifications shouldn't be added.
+pub fn remove_rule(rules: &mut Vec<json::Rule>, name: &str, rule: &json::Rule, merge: Merge) -> Result<Option<Merged>, Error> {
+    let index = match rules.iter().position(|r| is_same_rule(r, name, rule)) {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+
+    let untracked = rules[index].karaconv_source.is_none() && rule.karaconv_source.is_some();
+    Ok(match merge {
+        Merge::Replace if untracked => Some(Merged::SkippedUntracked(rules[index].description.clone())),
+        Merge::Replace | Merge::ReplaceUntracked => {
+            rules.remove(index);
+            None
+        }
+        Merge::SkipExisting | Merge::AppendManipulators | Merge::FailOnConflict => Some(add_rule(rules, name, rule.clone(), merge)?),
+    })
+}
+
+/// Hash of the parts of a rule that karaconv writes (description and manipulators)
+///
+/// Object keys are sorted first, because Karabiner-Elements reorders them when it saves the file.
+/// The hash is 64-bit FNV-1a, which is plenty to notice that a rule was edited.
+pub fn rule_hash(rule: &json::Rule) -> Result<String, Error> {
+    fn sorted(value: Value) -> Value {
+        match value {
+            Value::Object(map) => {
+                let mut entries = map.into_iter().collect::<Vec<_>>();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                Value::Object(entries.into_iter().map(|(k, v)| (k, sorted(v))).collect())
+            }
+            Value::Array(values) => Value::Array(values.into_iter().map(sorted).collect()),
+            value => value,
+        }
+    }
+
+    let content = Value::Array(vec![Value::String(rule.description.clone()), serde_json::to_value(&rule.manipulators)?]);
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for byte in serde_json::to_string(&sorted(content))?.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+/// Whether a converted rule was changed since karaconv wrote it
+///
+/// Rules without a hash count as changed, since there's no way to tell.
+pub fn is_hand_edited(rule: &json::Rule) -> Result<bool, Error> {
+    match rule.karaconv_source.as_ref().and_then(|s| s.hash.as_ref()) {
+        Some(hash) => Ok(*hash != rule_hash(rule)?),
+        None => Ok(true),
+    }
+}
+
+/// Other rules with manipulators for the same key, modifiers and conditions as this one
+///
+/// Returns the description of each of those rules and the key (with modifiers) they share. The
+/// rule that would be replaced by this one (the one for `name`) doesn't count.
+pub fn rule_conflicts(rules: &[json::Rule], name: &str, rule: &json::Rule) -> Vec<(String, String)> {
+    use json::KeyOrButtonConv;
+
+    fn trigger(manipulator: &json::Manipulator) -> Option<(json::KeyOrButton, Vec<String>, &[json::Condition])> {
+        let (key, modifiers) = manipulator.from.key_or_button()?;
+        let mut modifiers = modifiers.to_vec();
+        modifiers.sort();
+        Some((key, modifiers, &manipulator.conditions))
+    }
+
+    let mut conflicts = vec![];
+    for other in rules.iter().filter(|r| !is_same_rule(r, name, rule)) {
+        for theirs in other.manipulators.iter().filter_map(trigger) {
+            if rule.manipulators.iter().filter_map(trigger).any(|ours| ours == theirs) {
+                let (key, modifiers, _) = theirs;
+                let key = Key { key, modifiers, flags: json::ToFlags::default() };
+                let conflict = (other.description.clone(), key.describe());
+                if !conflicts.contains(&conflict) {
+                    conflicts.push(conflict);
+                }
+            }
+        }
+    }
+    conflicts
+}
+
+/// Existing simple modifications that would be replaced by different ones
+pub fn simple_modification_conflicts<'a>(existing: &'a [json::SimpleModification], new: &[json::SimpleModification])
+    -> Vec<&'a json::SimpleModification>
+{
+    existing.iter()
+            .filter(|e| new.iter().any(|n| n.from == e.from && n.to_events() != e.to_events()))
+            .collect()
+}
+
+/// Add a simple modification, replacing any existing one for the same key
+///
+/// Returns whether one was replaced.
+pub fn add_simple_modification(modifications: &mut Vec<json::SimpleModification>, modification: json::SimpleModification) -> bool {
+    match modifications.iter_mut().find(|m| m.from.key_code == modification.from.key_code) {
+        Some(existing) => {
+            *existing = modification;
+            true
+        }
+        None => {
+            modifications.push(modification);
+            false
+        }
+    }
+}
+
+/// Filters accumulated from enclosing blocks
+#[derive(Clone, Default)]
+struct Scope {
+    conditions: Vec<json::Condition>,
+    mandatory: Vec<String>,
+
+    /// Modifiers excluded by `<modifier_not>`
+    excluded: Vec<String>,
+
+    has_app_filter: bool,
+}
+
+/// Whether optional modifiers (which may be generic, like `shift`, or `any`) include a modifier
+pub fn allows_modifier(optional: &[String], modifier: &str) -> bool {
+    optional.iter().any(|o| o == "any" || o == modifier || modifier.strip_suffix(&**o).is_some_and(|side| side.ends_with('_')))
+}
+
+/// Convert an item to a rule
+pub fn convert_item(item: &xml::Item, context: &Context) -> Result<Converted, Error> {
+    let mut converted = Converted {
+        rule: json::Rule {
+            description: item.name.clone(),
+            manipulators: vec![],
+            karaconv_notes: None,
+            karaconv_source: match item.identifier.name.as_str() {
+                "" => None,
+                identifier => Some(json::Source { file: None, hash: None, identifier: identifier.into() }),
+            },
+            other: json::Other::new(),
+        },
+        keyboard_type: None,
+        losses: vec![],
+        notes: json::Notes {
+            appendix: item.appendix.clone(),
+            ..json::Notes::default()
+        },
+    };
+
+    let mut scope = Scope::default();
+    if item.identifier.is_vk_config() {
+        scope.conditions.push(VkConfigs::condition(&item.identifier.name, true));
+    }
+
+    convert_block(item, &item.block, &scope, context, &mut converted)?;
+    Ok(converted)
+}
+
+/// Convert a block, adding its filters to the enclosing ones (all of which must be satisfied)
+fn convert_block(item: &xml::Item, block: &xml::Block, scope: &Scope, context: &Context, converted: &mut Converted) -> Result<(), Error> {
+    converted.notes.comments.extend(block.comments.iter().cloned());
+
+    let mut scope = scope.clone();
+    let has_app_filter = scope.has_app_filter || block.filters.iter().any(|f| f.name == "only");
+    scope.has_app_filter = has_app_filter;
+    for filter in &block.filters {
+        match &*filter.name {
+            "only" | "not" => {
+                let apps = json::Applications {
+                    bundle_identifiers: context.appdefs.bundle_identifiers(&filter.value)?,
+                    ..json::Applications::default()
+                };
+                scope.conditions.push(if filter.name == "only" {
+                    json::Condition::FrontmostApplicationIf(apps)
+                } else {
+                    json::Condition::FrontmostApplicationUnless(apps)
+                });
+            }
+
+            "keyboardtype_only" | "keyboardtype_not" => {
+                let keyboard_types = if filter.name == "keyboardtype_only" {
+                    collect_keyboard_types(Some(&filter.value), None)?
+                } else {
+                    collect_keyboard_types(None, Some(&filter.value))?
+                };
+                scope.conditions.push(json::Condition::KeyboardTypeIf(json::KeyboardTypes {
+                    keyboard_types,
+                    ..json::KeyboardTypes::default()
+                }));
+            }
+
+            "device_only" | "device_not" => {
+                let devices = json::Devices {
+                    identifiers: context.devicedefs.devices(&filter.value)?,
+                    ..json::Devices::default()
+                };
+                scope.conditions.push(if filter.name == "device_only" {
+                    json::Condition::DeviceIf(devices)
+                } else {
+                    json::Condition::DeviceUnless(devices)
+                });
+            }
+
+            "inputsource_only" | "inputsource_not" => {
+                let input_sources = json::InputSources {
+                    input_sources: context.inputsourcedefs.input_sources(&filter.value)?,
+                    ..json::InputSources::default()
+                };
+                scope.conditions.push(if filter.name == "inputsource_only" {
+                    json::Condition::InputSourceIf(input_sources)
+                } else {
+                    json::Condition::InputSourceUnless(input_sources)
+                });
+            }
+
+            "modifier_only" | "modifier_not" => {
+                let mut args = autogen::parse_args(&filter.value)?.into_iter();
+                let flags = match (args.next(), args.next()) {
+                    (Some(flags), None) => flags,
+                    _ => bail!("Alternatives in <{}> are not supported: {}", filter.name, filter.value),
+                };
+
+                let mut real = vec![];
+                for flag in flags.symbols {
+                    let variable = if flag.namespace() == "ModifierFlag" { extra_modifier_variable(flag.name()) } else { None };
+                    match variable {
+                        Some(variable) => scope.conditions.push(VkConfigs::condition(&variable, filter.name == "modifier_only")),
+                        None => real.push(flag),
+                    }
+                }
+
+                // other modifiers already prevent a manipulator from matching, unless they are optional
+                // (which is checked for each manipulator below)
+                if !real.is_empty() {
+                    let modifiers = conv_mod(&autogen::Arg { symbols: real })?;
+                    if filter.name == "modifier_only" {
+                        scope.mandatory.extend(modifiers);
+                    } else {
+                        scope.excluded.extend(modifiers);
+                    }
+                }
+            }
+
+            "windowname_only" => {
+                // without the application, dropping the window name would make the rule apply everywhere
+                if !has_app_filter {
+                    bail!("<windowname_only>{}</windowname_only> needs an <only> filter for the application", filter.value);
+                }
+                converted.losses.push(format!("window name filter ({}) dropped, so the rule applies to all windows of the application",
+                                              filter.value));
+            }
+
+            "windowname_not" => {
+                converted.losses.push(format!("window name filter ({}) dropped, so the rule also applies to those windows",
+                                              filter.value));
+            }
+
+            "uielementrole_only" | "uielementrole_not" => {
+                converted.losses.push(format!("UI element role filter ({}) dropped, so the rule {}",
+                                              filter.value,
+                                              if filter.name == "uielementrole_only" { "applies to all elements" } else { "also applies to those elements" }));
+            }
+
+            "lastpressedphysicalkey_only" | "lastpressedphysicalkey_not" => {
+                converted.losses.push(format!("last pressed key filter ({}) dropped, so the rule applies regardless of the previous key",
+                                              filter.value));
+            }
+
+            "elapsedtimesincelastpressed_greaterthan" | "elapsedtimesincelastpressed_lessthan" => {
+                converted.losses.push(format!("elapsed time filter ({}) dropped, so the rule applies regardless of timing",
+                                              filter.value));
+            }
+
+            _ => bail!("Unsupported filter <{}>", filter.name)
+        }
+    }
+
+    for content in &block.contents {
+        match *content {
+            xml::BlockContent::Autogen(ref autogen) => {
+                if !autogen.comments.is_empty() {
+                    converted.notes.autogens.push(json::AutogenNote {
+                        autogen: autogen.text.clone(),
+                        comments: autogen.comments.clone(),
+                    });
+                }
+
+                let first = converted.rule.manipulators.len();
+                convert_autogen(item, &autogen.text, &scope, context, converted)?;
+
+                for manipulator in &mut converted.rule.manipulators[first..] {
+                    let modifiers = match manipulator.from.modifiers_mut() {
+                        Some(modifiers) => modifiers,
+                        None => continue,
+                    };
+                    for modifier in &scope.mandatory {
+                        if !modifiers.mandatory.contains(modifier) {
+                            modifiers.mandatory.push(modifier.clone());
+                        }
+                    }
+
+                    for modifier in &scope.excluded {
+                        if allows_modifier(&modifiers.optional, modifier) {
+                            let loss = format!("<modifier_not> for {} dropped, since the key to remap ({}) allows it as an optional modifier",
+                                               modifier, autogen.text);
+                            if !converted.losses.contains(&loss) {
+                                converted.losses.push(loss);
+                            }
+                        }
+                    }
+                }
+            }
+
+            xml::BlockContent::Block(ref block) => convert_block(item, block, &scope, context, converted)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Take the key to remap (the first one) from the keys of an autogen
+///
+/// A wait after it (`KeyCode::VK_WAIT_$nMS`) has no equivalent on a from-event, so it's dropped.
+fn from_key<I: Iterator<Item=Event>>(keys: &mut I, autogen: &autogen::Autogen, losses: &mut Vec<String>)
+    -> Result<json::From, Error>
+{
+    let key = keys.next().ok_or_else(|| format_err!("Missing key to remap in {}", autogen))?.into_key()?;
+    if let Some(ms) = key.flags.hold_down_milliseconds {
+        losses.push(format!("{}ms wait after the key to remap ({}) dropped", ms, key.describe()));
+    }
+    Ok(key.into_from())
+}
+
+/// Convert a single autogen
+fn convert_autogen(item: &xml::Item, autogen: &str, scope: &Scope, context: &Context, converted: &mut Converted) -> Result<(), Error> {
+    let autogen = autogen::Autogen::parse(autogen)?;
+    match &*autogen.type_ {
+        "KeyToKey" => {
+            let mut keys = collect_keys(&autogen.args, &mut converted.losses)?.into_iter();
+            let fromkey = from_key(&mut keys, &autogen, &mut converted.losses)?;
+            converted.rule.manipulators.extend(build_manipulators(fromkey, keys.collect(), vec![],
+                                                                  &scope.conditions, &context.configs,
+                                                                  &mut converted.losses)?);
+        }
+
+        "KeyOverlaidModifier" => {
+            let mut keys = collect_keys(&autogen.args, &mut converted.losses)?.into_iter();
+            let fromkey = from_key(&mut keys, &autogen, &mut converted.losses)?;
+            let tokey = keys.next().ok_or_else(|| format_err!("Missing modifier in {}", autogen))?;
+            converted.rule.manipulators.extend(build_manipulators(fromkey, vec![tokey], keys.collect(),
+                                                                  &scope.conditions, &context.configs,
+                                                                  &mut converted.losses)?);
+        }
+
+        "ForceNumLockOn" => {
+            converted.losses.push("NumLock can't be forced on, so --ForceNumLockOn-- was dropped".into());
+        }
+
+        "SetKeyboardType" => {
+            let symbol = match &*autogen.args {
+                [autogen::Arg { symbols }] if symbols.len() == 1 => &symbols[0],
+                _ => bail!("Expected a single keyboard type in {}", autogen),
+            };
+            converted.keyboard_type = Some(conv_keyboard_type(symbol)?);
+        }
+
+        "ShowStatusMessage" => {
+            if !item.identifier.is_vk_config() {
+                converted.losses.push("status message dropped (only supported for vk_config items)".into());
+            }
+        }
+
+        otherwise => bail!("Unsupported autogen type: {}", otherwise)
+    }
+
+    Ok(())
+}
@@ -1,118 +1,780 @@
 //! Utility to convert from Karabiner XML format to Karabiner-Elements JSON format
 
-#[macro_use] extern crate structopt;
+extern crate structopt;
 #[macro_use] extern crate failure;
 extern crate serde;
 extern crate chrono;
-extern crate regex;
 extern crate serde_json;
-extern crate serde_xml_rs as serde_xml;
 
 extern crate karaconv;
 
 use failure::Error;
-use regex::Regex;
 use structopt::StructOpt;
 
-use karaconv::{xml, json};
-use json::KeyOrButtonConv;
+use karaconv::{xml, json, prefs, builtin, seil, modmap};
 
+use std::collections::BTreeSet;
 use std::fs::{self, File};
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Where to put the appendixes and comments of items
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NotesDestination {
+    /// After the name in the rule description
+    Description,
+
+    /// In a `karaconv_notes` field of the rule
+    Field,
+
+    /// In a separate Markdown file
+    File,
+}
+
+impl FromStr for NotesDestination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "description" => Ok(NotesDestination::Description),
+            "field" => Ok(NotesDestination::Field),
+            "file" => Ok(NotesDestination::File),
+            _ => Err(format!("unknown notes destination {} (expected description, field or file)", s)),
+        }
+    }
+}
 
 #[derive(Debug, StructOpt)]
 struct Opt {
-    /// Input file (Karabiner XML)
+    /// Input file (Karabiner XML, required unless using a subcommand)
     #[structopt(short="i", long="input", parse(from_os_str))]
-    infile: PathBuf,
+    infile: Option<PathBuf>,
 
-    /// Output file (Karabiner-Elements JSON)
+    /// Output file (Karabiner-Elements JSON, required unless using --asset or a subcommand)
     #[structopt(short="o", long="output", parse(from_os_str))]
-    outfile: PathBuf,
+    outfile: Option<PathBuf>,
 
-    /// Which profile to modify in the JSON
-    #[structopt(short="p", long="profile", default_value="0")]
-    profile: usize,
+    /// Which profile to modify in the JSON, by name or index (can be repeated; default: 0)
+    #[structopt(short="p", long="profile")]
+    profiles: Vec<String>,
+
+    /// Create the profiles given with -p that don't exist yet
+    #[structopt(long="create-profile")]
+    create_profile: bool,
+
+    /// Start new profiles as a copy of this one instead of empty
+    #[structopt(long="from-profile", raw(requires="\"create_profile\""))]
+    from_profile: Option<String>,
 
     /// Dry run
     #[structopt(short="n")]
     dry_run: bool,
+
+    /// Mention approximated features in rule descriptions
+    #[structopt(long="describe-losses")]
+    describe_losses: bool,
+
+    /// Keep item appendixes and comments, in the rule "description", a "field" or a separate "file"
+    #[structopt(long="notes")]
+    notes: Option<NotesDestination>,
+
+    /// Where to write the notes with --notes=file (default: next to the output file)
+    #[structopt(long="notes-file", parse(from_os_str))]
+    notes_file: Option<PathBuf>,
+
+    /// Version of Karabiner-Elements to target (default: latest)
+    #[structopt(long="elements-version")]
+    elements_version: Option<karaconv::Version>,
+
+    /// Only convert items that are enabled in Karabiner's preferences
+    #[structopt(long="only-enabled")]
+    only_enabled: bool,
+
+    /// Karabiner preferences file (implies --only-enabled; default: ~/Library/Preferences/org.pqrs.Karabiner.plist)
+    #[structopt(long="prefs", parse(from_os_str))]
+    prefs: Option<PathBuf>,
+
+    /// Shell script made by Karabiner's "Export" button (implies --only-enabled)
+    #[structopt(long="export", parse(from_os_str), raw(conflicts_with="\"prefs\""))]
+    export: Option<PathBuf>,
+
+    /// Also convert this built-in Karabiner setting (e.g. remap.commandR2optionR)
+    #[structopt(long="builtin")]
+    builtins: Vec<String>,
+
+    /// Write the rules to a complex_modifications asset file, to be added in Karabiner-Elements one by one,
+    /// instead of changing karabiner.json
+    #[structopt(long="asset", parse(from_os_str),
+                raw(conflicts_with_all=r#"&["outfile", "profiles", "create_profile", "seil", "modifier_mappings", "simple_modifications",
+                                            "prune"]"#))]
+    asset: Option<PathBuf>,
+
+    /// Write a separate asset file for each item (--asset is then a directory)
+    #[structopt(long="asset-per-item", raw(requires="\"asset\""))]
+    asset_per_item: bool,
+
+    /// Title of the asset file (put before the item name with --asset-per-item)
+    #[structopt(long="asset-title")]
+    asset_title: Option<String>,
+
+    /// Also import Seil's (or PCKeyboardHack's) preferences file, as simple modifications
+    #[structopt(long="seil", parse(from_os_str))]
+    seil: Option<PathBuf>,
+
+    /// Also import modifier key remappings from System Preferences (an exported global preferences plist)
+    #[structopt(long="modifier-mappings", parse(from_os_str))]
+    modifier_mappings: Option<PathBuf>,
+
+    /// Put imported modifier key remappings in the profile instead of the devices they were for
+    #[structopt(long="modifier-mappings-for-all-devices")]
+    modifier_mappings_for_all_devices: bool,
+
+    /// What to do with rules that are already there: replace, replace-untracked, skip-existing, append-manipulators or fail-on-conflict
+    #[structopt(long="merge", default_value="replace")]
+    merge: karaconv::Merge,
+
+    /// Remove rules converted from items that are no longer in the input file (asks about rules changed since)
+    #[structopt(long="prune")]
+    prune: bool,
+
+    /// Put items that only remap single keys in simple modifications instead of complex rules
+    #[structopt(long="simple-modifications")]
+    simple_modifications: bool,
+
+    /// Which Karabiner profile to check for enabled items, by name or index (default: the selected one)
+    #[structopt(long="karabiner-profile")]
+    karabiner_profile: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Tidy up a private.xml file (re-indent, normalize autogens and sort application definitions)
+    #[structopt(name="fmt")]
+    Fmt {
+        /// File to format (in place, after backing it up)
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Indentation width
+        #[structopt(long="indent", default_value="4")]
+        indent: usize,
+
+        /// Dry run (print the result)
+        #[structopt(short="n")]
+        dry_run: bool,
+    },
+
+    /// Merge rules from a complex_modifications asset file into karabiner.json
+    #[structopt(name="import-asset")]
+    ImportAsset {
+        /// Asset file
+        #[structopt(parse(from_os_str))]
+        asset: PathBuf,
+
+        /// Karabiner-Elements JSON file to change
+        #[structopt(short="o", long="output", parse(from_os_str))]
+        outfile: PathBuf,
+
+        /// Only import the rule with this description (can be repeated; default: all of them)
+        #[structopt(long="rule")]
+        rules: Vec<String>,
+
+        /// Name or index of the profile to add the rules to (default: the selected one)
+        #[structopt(long="profile")]
+        profile: Option<String>,
+
+        /// What to do with rules that are already there: replace, replace-untracked, skip-existing, append-manipulators or fail-on-conflict
+        #[structopt(long="merge", default_value="replace")]
+        merge: karaconv::Merge,
+
+        /// Dry run (print the result)
+        #[structopt(short="n")]
+        dry_run: bool,
+    },
+}
+
+/// Reformat private.xml
+fn fmt(file: &Path, indent: usize, dry_run: bool) -> Result<(), Error> {
+    let mut doc = xml::Document::parse(File::open(file)?)?;
+    doc.format(&" ".repeat(indent));
+
+    if dry_run {
+        print!("{}", doc);
+    } else {
+        let backup = backup(file)?;
+        write!(File::create(file)?, "{}", doc)?;
+        println!("Done! The old file is backed up at {}.", backup.display());
+    }
+    Ok(())
+}
+
+/// Merge rules from an asset file into a profile
+fn import_asset(asset: &Path, outfile: &Path, names: &[String], profile: Option<&str>, merge: karaconv::Merge, dry_run: bool)
+    -> Result<(), Error>
+{
+    let asset: json::Asset = serde_json::from_reader(File::open(asset)?)?;
+    let mut outjson: json::Karabiner = serde_json::from_reader(File::open(outfile)?)?;
+
+    for name in names {
+        if !asset.rules.iter().any(|r| r.description == *name) {
+            let available = asset.rules.iter().map(|r| &*r.description).collect::<Vec<_>>();
+            bail!("There is no rule \"{}\" in {} (it has: {})", name, asset.title, available.join("; "));
+        }
+    }
+
+    {
+        let profile = match profile {
+            Some(name) => {
+                let index = outjson.find_profile(name).ok_or_else(|| no_profile(&outjson, name))?;
+                &mut outjson.profiles[index]
+            }
+            None => outjson.selected_profile_mut().ok_or_else(|| format_err!("No profile is selected"))?,
+        };
+        let rules = &mut profile.complex_modifications.rules;
+        for rule in asset.rules {
+            if !names.is_empty() && !names.contains(&rule.description) {
+                continue;
+            }
+
+            print!("Importing {}... ", rule.description);
+            let conflicts = karaconv::rule_conflicts(rules, &rule.description, &rule);
+            let name = rule.description.clone();
+            println!("{}", karaconv::add_rule(rules, &name, rule, merge)?);
+            for (other, key) in conflicts {
+                println!("Warning: {} is also remapped by \"{}\"", key, other);
+            }
+        }
+    }
+
+    if dry_run {
+        println!("{}", serde_json::to_string_pretty(&outjson)?);
+    } else {
+        let backup = save(outfile, &outjson)?;
+        println!("Done! Your old config is backed up at {}.", backup.display());
+    }
+    Ok(())
+}
+
+/// Find (or create) the profiles to convert into
+fn profile_targets(opt: &Opt, outjson: &mut json::Karabiner) -> Result<Vec<usize>, Error> {
+    let template = match opt.from_profile {
+        Some(ref name) => Some(outjson.find_profile(name).ok_or_else(|| no_profile(outjson, name))?),
+        None => None,
+    };
+
+    let first = ["0".to_string()];
+    let names = if opt.profiles.is_empty() { &first[..] } else { &opt.profiles[..] };
+    let mut targets = vec![];
+    for name in names {
+        let index = match outjson.find_profile(name) {
+            Some(index) => index,
+            None if opt.create_profile => {
+                println!("Creating profile {}", name);
+                outjson.add_profile(name, template)
+            }
+            None => bail!("{}; pass --create-profile to add it", no_profile(outjson, name)),
+        };
+        if !targets.contains(&index) {
+            targets.push(index);
+        }
+    }
+    Ok(targets)
+}
+
+fn no_profile(outjson: &json::Karabiner, name: &str) -> Error {
+    let profiles = outjson.profiles.iter().enumerate().map(|(i, p)| format!("{}: {}", i, p.name)).collect::<Vec<_>>();
+    format_err!("There is no profile {} (there are {})", name, profiles.join(", "))
+}
+
+/// What happened to the rules of one profile
+#[derive(Default)]
+struct Summary {
+    added: Vec<String>,
+    replaced: Vec<String>,
+    appended: Vec<String>,
+    skipped: Vec<String>,
+    simple: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl Summary {
+    /// Note what `add_rule` did with an item's rule
+    fn record(&mut self, merged: &karaconv::Merged, name: &str) {
+        match *merged {
+            karaconv::Merged::Added => &mut self.added,
+            karaconv::Merged::Replaced | karaconv::Merged::ReplacedUntracked(_) => &mut self.replaced,
+            karaconv::Merged::Appended(_) => &mut self.appended,
+            karaconv::Merged::Skipped | karaconv::Merged::SkippedUntracked(_) => &mut self.skipped,
+        }.push(name.to_string());
+    }
+}
+
+/// Ask a yes/no question on the terminal (no answer means no)
+fn confirm(question: &str) -> Result<bool, Error> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(["y", "yes"].contains(&&*answer.trim().to_lowercase()))
+}
+
+/// Copy a file to `<name>.<extension>.bak.<date>`, and return the path of the copy
+fn backup(file: &Path) -> Result<PathBuf, Error> {
+    let extension = file.extension().map_or(String::new(), |e| format!("{}.", e.to_string_lossy()));
+    let backup = file.with_extension(format!("{}bak.{}", extension, chrono::Local::now().format("%Y%m%d")));
+    fs::copy(file, &backup)?;
+    Ok(backup)
+}
+
+/// Write karabiner.json, backing up the old one
+///
+/// Returns the path of the backup.
+fn save(outfile: &Path, outjson: &json::Karabiner) -> Result<PathBuf, Error> {
+    let outfile_backup = backup(outfile)?;
+    serde_json::to_writer_pretty(File::create(outfile)?, outjson)?;
+    Ok(outfile_backup)
 }
 
 fn try_main() -> Result<(), Error> {
     let opt = Opt::from_args();
+    match opt.command {
+        Some(Command::Fmt { ref file, indent, dry_run }) => return fmt(file, indent, dry_run),
+        Some(Command::ImportAsset { ref asset, ref outfile, ref rules, ref profile, merge, dry_run }) =>
+            return import_asset(asset, outfile, rules, profile.as_deref(), merge, dry_run),
+        None => {}
+    }
 
-    let inxml: xml::Karabiner = serde_xml::deserialize(File::open(&opt.infile)?)?;
-    let mut outjson: json::Karabiner = serde_json::from_reader(File::open(&opt.outfile)?)?;
+    let infile = match opt.infile {
+        Some(ref infile) => infile,
+        None => bail!("--input is required"),
+    };
 
-    for item in inxml.items {
+    let inxml = xml::Karabiner::parse(File::open(infile)?)?;
+    let mut outjson: json::Karabiner = match (&opt.outfile, &opt.asset) {
+        (Some(outfile), _) => serde_json::from_reader(File::open(outfile)?)?,
+        // asset files only hold rules, so there's no profile to change
+        (None, Some(_)) => json::Karabiner::default(),
+        (None, None) => bail!("Either --output or --asset is required"),
+    };
+    let mut asset_rules = vec![];
+    let targets = match opt.asset {
+        Some(_) => vec![],
+        None => profile_targets(&opt, &mut outjson)?,
+    };
+    let mut summaries = targets.iter().map(|_| Summary::default()).collect::<Vec<_>>();
+    // tell the profiles apart in messages when there are several
+    let into = targets.iter().map(|&i| if targets.len() > 1 {
+        format!(" into {}", outjson.profiles[i].name)
+    } else {
+        String::new()
+    }).collect::<Vec<_>>();
+
+    let mut context = karaconv::Context::new(&inxml)?;
+    if let Some(version) = opt.elements_version {
+        if version < karaconv::Version::NOTIFICATION_MESSAGE && context.configs.has_status_messages() {
+            println!("Warning: Karabiner-Elements {} can't show status messages (they need {}), so they will be dropped",
+                     version, karaconv::Version::NOTIFICATION_MESSAGE);
+            context.configs.clear_status_messages();
+        }
+    }
+
+    let prefs = match (&opt.prefs, &opt.export) {
+        (Some(path), _) => Some(prefs::Preferences::open(path)?),
+        (_, Some(path)) => Some(prefs::Preferences::open_export_script(path)?),
+        _ if opt.only_enabled => Some(prefs::Preferences::open(prefs::Preferences::default_path()?)?),
+        _ => None,
+    };
+    let enabled = match prefs {
+        Some(ref prefs) => Some(prefs.profile(opt.karabiner_profile.as_deref())?),
+        None => None,
+    };
+
+    if let (Some(enabled), Some(_)) = (enabled, &opt.asset) {
+        if enabled.settings.keys().any(|name| prefs::is_parameter(name)) {
+            println!("Warning: asset files can only hold rules, so parameters from Karabiner's preferences are not converted");
+        }
+    } else if let Some(enabled) = enabled {
+        for (&index, into) in targets.iter().zip(&into) {
+            let (applied, dropped) = enabled.apply_parameters(&mut outjson.profiles[index]);
+            for param in applied {
+                println!("Setting parameter {}{}", param, into);
+            }
+            // the same for every profile
+            if index == targets[0] {
+                for param in dropped {
+                    println!("Warning: parameter {} has no equivalent in Karabiner-Elements, so it will be dropped", param);
+                }
+            }
+        }
+    }
+
+    if let Some(ref path) = opt.seil {
+        let seil = seil::Seil::open(path)?;
+        for (&index, into) in targets.iter().zip(&into) {
+            let simple_modifications = &mut outjson.profiles[index].simple_modifications;
+            for modification in seil.simple_modifications.clone() {
+                print!("Importing Seil setting for {}{}... ", modification.from, into);
+                if karaconv::add_simple_modification(simple_modifications, modification) {
+                    println!("replacing existing simple modification");
+                } else {
+                    println!("adding new simple modification");
+                }
+            }
+        }
+        for name in seil.unknown {
+            println!("Warning: Seil setting {} is not supported, so it will be dropped", name);
+        }
+    }
+
+    if let Some(ref path) = opt.modifier_mappings {
+        let mappings = modmap::ModifierMappings::open(path)?;
+        let clashes = if opt.modifier_mappings_for_all_devices { mappings.clashes() } else { BTreeSet::new() };
+        for key in &clashes {
+            println!("Warning: devices map {} differently, so its modifier mappings can't be imported for all devices", key);
+        }
+        for (&index, into) in targets.iter().zip(&into) {
+            let profile = &mut outjson.profiles[index];
+            for device in &mappings.devices {
+                let simple_modifications = if opt.modifier_mappings_for_all_devices {
+                    &mut profile.simple_modifications
+                } else {
+                    &mut profile.keyboard_mut(device.vendor_id, device.product_id).simple_modifications
+                };
+
+                for modification in device.simple_modifications.clone() {
+                    if clashes.contains(&modification.from.to_string()) {
+                        continue;
+                    }
+                    print!("Importing modifier mapping for {} on device {}:{}{}... ",
+                           modification.from, device.vendor_id, device.product_id, into);
+                    if karaconv::add_simple_modification(simple_modifications, modification) {
+                        println!("replacing existing simple modification");
+                    } else {
+                        println!("adding new simple modification");
+                    }
+                }
+            }
+        }
+        for mapping in mappings.unknown {
+            println!("Warning: modifier mapping {} is not supported, so it will be dropped", mapping);
+        }
+    }
+
+    let mut items = vec![];
+    for item in &inxml.items {
+        if let Some(enabled) = enabled {
+            // vk_config items are switched on by keys, not in the preferences
+            if !item.identifier.is_vk_config() && !enabled.is_enabled(&item.identifier.name) {
+                println!("Skipping {} (not enabled in Karabiner profile {})", item.name, enabled.label());
+                continue;
+            }
+        }
+        items.push(item);
+    }
+
+    // built-in settings: the ones asked for, and the enabled ones that aren't in private.xml
+    let builtins = builtin::Builtins::load()?;
+    let mut unknown = BTreeSet::new();
+    let wanted = opt.builtins.iter().map(|s| &**s)
+                    .chain(enabled.into_iter().flat_map(prefs::Profile::enabled))
+                    .filter(|&id| !inxml.items.iter().any(|item| item.identifier.name == id))
+                    .collect::<BTreeSet<_>>();
+    for identifier in wanted {
+        match builtins.get(identifier) {
+            Some(item) => items.push(item),
+            None => { unknown.insert(identifier); }
+        }
+    }
+
+    // the full path, since most of these files are called private.xml
+    let source_file = Some(infile.canonicalize()?.to_string_lossy().into_owned());
+    let mut approximated = vec![];
+    let mut notes = String::new();
+    for item in items {
         print!("Converting {}... ", item.name);
-        let mut rule = json::Rule {
-            description: item.name,
-            manipulators: vec![]
+        let mut converted = karaconv::convert_item(item, &context)?;
+        let builtin = !inxml.items.iter().any(|i| std::ptr::eq(i, item));
+
+        if !converted.losses.is_empty() {
+            print!("approximating, ");
+            approximated.push((item.name.clone(), converted.losses.clone()));
+            if opt.describe_losses {
+                converted.describe_losses();
+            }
+        }
+
+        match opt.notes {
+            Some(NotesDestination::Description) => converted.describe_notes(),
+            Some(NotesDestination::Field) => converted.attach_notes(),
+            Some(NotesDestination::File) => notes.push_str(&notes_markdown(&item.name, &converted.notes)),
+            None => {}
+        }
+
+        converted.record_source(if builtin { None } else { source_file.clone() })?;
+
+        if let (Some(keyboard_type), Some(_)) = (converted.keyboard_type, &opt.asset) {
+            print!("can't set keyboard type to {} in an asset file, ", keyboard_type);
+        } else if let Some(keyboard_type) = converted.keyboard_type {
+            print!("setting keyboard type to {}, ", keyboard_type);
+            for &index in &targets {
+                outjson.profiles[index].virtual_hid_keyboard.set_keyboard_type(keyboard_type);
+            }
+        }
+
+        // descriptions and notes fields would be lost in simple modifications
+        let simple = if opt.simple_modifications && converted.rule.description == item.name
+                        && converted.rule.karaconv_notes.is_none() {
+            converted.simple_modifications()
+        } else {
+            None
         };
-        
-        for key in item.keys {
-            let type_regex = Regex::new(r"--(?P<type>[a-zA-Z]+)-- (?P<contents>.*)")?;
-            if let Some(caps) = type_regex.captures(&key) {
-                let mut keys = karaconv::collect_keys(&caps["contents"])?;
-
-                match &caps["type"] {
-                    "KeyToKey" => {
-                        let (fromkey, frommod) = keys.remove(0);
-                        rule.manipulators.push(json::Manipulator {
-                            type_: "basic".into(),
-                            from: json::From::conv(fromkey, frommod),
-                            to: keys.into_iter().map(|(k, m)| json::To::conv(k, m)).collect(),
-                            to_if_alone: vec![],
-                        });
+        let rule = converted.rule;
+        if rule.manipulators.is_empty() {
+            println!("no rule needed");
+            continue;
+        }
+
+        if opt.asset.is_some() {
+            println!("adding rule to asset");
+            asset_rules.push((item, rule));
+            continue;
+        }
+
+        let mut messages = vec![];
+        for (&index, summary) in targets.iter().zip(&mut summaries) {
+            let profile = &mut outjson.profiles[index];
+            let mut message = String::new();
+            if let Some(ref modifications) = simple {
+                let conflicts = karaconv::simple_modification_conflicts(&profile.simple_modifications, modifications);
+                if conflicts.is_empty() {
+                    // the item's old rule has to go, if --merge allows it
+                    let kept = karaconv::remove_rule(&mut profile.complex_modifications.rules, &item.name, &rule, opt.merge)
+                        .map_err(|e| format_err!("{} in profile {}", e, profile.name))?;
+                    if let Some(merged) = kept {
+                        summary.record(&merged, &item.name);
+                        messages.push((profile.name.clone(), merged.to_string()));
+                        continue;
                     }
 
-                    "KeyOverlaidModifier" => {
-                        let (fromkey, frommod) = keys.remove(0);
-                        let (tokey, tomod) = keys.remove(0);
-                        rule.manipulators.push(json::Manipulator {
-                            type_: "basic".into(),
-                            from: json::From::conv(fromkey, frommod),
-                            to: vec![json::To::conv(tokey, tomod)],
-                            to_if_alone: keys.into_iter().map(|(k, m)| json::To::conv(k, m)).collect(),
-                        });
+                    for modification in modifications.clone() {
+                        karaconv::add_simple_modification(&mut profile.simple_modifications, modification);
                     }
+                    summary.simple.push(item.name.clone());
+                    messages.push((profile.name.clone(), "adding simple modifications".to_string()));
+                    continue;
+                }
+
+                let keys = conflicts.iter().map(|c| c.from.to_string()).collect::<Vec<_>>();
+                message = format!("keeping it a complex rule ({} already remapped differently in simple modifications), ",
+                                  keys.join(", "));
+            }
 
-                    otherwise => bail!("Unsupported autogen type: {}", otherwise)
+            let merged = karaconv::add_rule(&mut profile.complex_modifications.rules, &item.name, rule.clone(), opt.merge)
+                .map_err(|e| format_err!("{} in profile {}", e, profile.name))?;
+            summary.record(&merged, &item.name);
+            message += &merged.to_string();
+            messages.push((profile.name.clone(), message));
+        }
+        if let [(_, ref message)] = messages[..] {
+            println!("{}", message);
+        } else {
+            let messages = messages.iter().map(|(name, message)| format!("{}: {}", name, message)).collect::<Vec<_>>();
+            println!("{}", messages.join("; "));
+        }
+    }
+
+    if opt.prune {
+        let identifiers = inxml.items.iter().map(|i| &*i.identifier.name).collect::<BTreeSet<_>>();
+        for ((&index, summary), into) in targets.iter().zip(&mut summaries).zip(&into) {
+            let rules = &mut outjson.profiles[index].complex_modifications.rules;
+            let mut kept = vec![];
+            for rule in rules.drain(..) {
+                let gone = match rule.karaconv_source {
+                    Some(ref source) => source.file.is_some() && source.file == source_file
+                                        && !identifiers.contains(&*source.identifier),
+                    None => false,
+                };
+                if !gone {
+                    kept.push(rule);
+                    continue;
+                }
+
+                let identifier = &rule.karaconv_source.as_ref().expect("checked above").identifier;
+                if karaconv::is_hand_edited(&rule)? {
+                    let question = format!("Rule \"{}\"{} was converted from {}, which is gone, but it has been changed since. Remove it anyway?",
+                                           rule.description, into, identifier);
+                    if !confirm(&question)? {
+                        println!("Keeping it");
+                        kept.push(rule);
+                        continue;
+                    }
+                } else {
+                    println!("Removing rule \"{}\"{} ({} is gone)", rule.description, into, identifier);
                 }
-            } else {
-                bail!("Unparseable autogen: {}", key);
+                summary.removed.push(rule.description);
             }
+            *rules = kept;
         }
+    }
 
-        let mut done = false;
-        if let Some(existing_rule) = outjson.profiles[opt.profile].complex_modifications.rules.iter_mut().find(|r| r.description == rule.description) {
-            println!("replacing existing rule");
-            existing_rule.manipulators = rule.manipulators.clone();
-            done = true;
+    if targets.len() > 1 {
+        for (&index, summary) in targets.iter().zip(&summaries) {
+            println!("Profile {}:", outjson.profiles[index].name);
+            let kinds = [("added", &summary.added), ("replaced", &summary.replaced),
+                         ("merged into existing rules", &summary.appended), ("skipped", &summary.skipped),
+                         ("as simple modifications", &summary.simple), ("removed", &summary.removed)];
+            for &(what, names) in &kinds {
+                if !names.is_empty() {
+                    println!("- {} {}: {}", names.len(), what, names.join(", "));
+                }
+            }
+            if kinds.iter().all(|(_, names)| names.is_empty()) {
+                println!("- no rules changed");
+            }
         }
-        if !done {
-            println!("adding new rule");
-            outjson.profiles[opt.profile].complex_modifications.rules.push(rule);
+    }
+
+    if !approximated.is_empty() {
+        println!("Some items use features that Karabiner-Elements doesn't have, so they were approximated:");
+        for (name, losses) in approximated {
+            println!("- {}:", name);
+            for loss in losses {
+                println!("    - {}", loss);
+            }
         }
     }
 
+    if !unknown.is_empty() {
+        println!("These settings aren't in private.xml or karaconv's list of built-in settings, so they were not converted:");
+        for identifier in unknown {
+            println!("- {}", identifier);
+        }
+    }
+
+    if let Some(ref path) = opt.asset {
+        let title = opt.asset_title.clone().unwrap_or_else(|| {
+            format!("Converted from {}", infile.file_name().map_or("private.xml".into(), |f| f.to_string_lossy()))
+        });
+        let notes_file = opt.notes_file.clone().unwrap_or_else(|| path.with_extension("notes.md"));
+        return write_assets(path, &title, asset_rules, opt.asset_per_item, &notes, &notes_file, opt.dry_run);
+    }
+
+    let outfile = opt.outfile.as_ref().expect("checked when reading it");
+    let notes_file = opt.notes_file.clone().unwrap_or_else(|| outfile.with_extension("notes.md"));
+
     if opt.dry_run {
         println!("{}", serde_json::to_string_pretty(&outjson)?);
+        if !notes.is_empty() {
+            println!("{}", notes);
+        }
     } else {
-        let outfile_backup = opt.outfile.with_extension(format!("{}.bak.{}",
-                                                                opt.outfile.extension().unwrap().to_str().unwrap(),
-                                                                chrono::Local::now().format("%Y%m%d")));
-        fs::copy(&opt.outfile, &outfile_backup)?;
-        serde_json::to_writer_pretty(File::create(&opt.outfile)?, &outjson)?;
+        if !notes.is_empty() {
+            File::create(&notes_file)?.write_all(notes.as_bytes())?;
+            println!("Notes written to {}.", notes_file.display());
+        }
 
+        let outfile_backup = save(outfile, &outjson)?;
         println!("Done! Your old config is backed up at {}.", outfile_backup.display());
     }
 
     Ok(())
 }
 
+/// Write converted rules to complex_modifications asset files
+///
+/// With `per_item`, `path` is a directory and each item gets its own file, titled with `title` and
+/// the item's name. Files are named after the items' identifiers (or names), numbered if two would
+/// have the same name.
+fn write_assets(path: &Path, title: &str, rules: Vec<(&xml::Item, json::Rule)>, per_item: bool,
+                notes: &str, notes_file: &Path, dry_run: bool)
+    -> Result<(), Error>
+{
+    let mut assets = vec![];
+    if per_item {
+        let mut used = BTreeSet::new();
+        for (item, rule) in rules {
+            // named after the identifier, or the name for items without one
+            let name = if item.identifier.name.is_empty() { &item.name } else { &item.identifier.name };
+            let name = name.chars()
+                           .map(|c| if c.is_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+                           .collect::<String>();
+            let name = if name.is_empty() { "item".to_string() } else { name };
+
+            // macOS file names are usually case-insensitive
+            let mut unique = name.clone();
+            let mut n = 1;
+            while !used.insert(unique.to_lowercase()) {
+                n += 1;
+                unique = format!("{}-{}", name, n);
+            }
+
+            let asset = json::Asset {
+                title: format!("{}: {}", title, item.name),
+                rules: vec![rule],
+                ..json::Asset::default()
+            };
+            assets.push((path.join(format!("{}.json", unique)), asset));
+        }
+    } else {
+        let asset = json::Asset {
+            title: title.into(),
+            rules: rules.into_iter().map(|(_, rule)| rule).collect(),
+            ..json::Asset::default()
+        };
+        assets.push((path.to_path_buf(), asset));
+    }
+
+    if dry_run {
+        for (path, asset) in &assets {
+            println!("{}:\n{}", path.display(), serde_json::to_string_pretty(asset)?);
+        }
+        if !notes.is_empty() {
+            println!("{}", notes);
+        }
+        return Ok(());
+    }
+
+    if per_item {
+        fs::create_dir_all(path)?;
+    }
+    for (path, asset) in &assets {
+        serde_json::to_writer_pretty(File::create(path)?, asset)?;
+        println!("Rules written to {}.", path.display());
+    }
+    if !notes.is_empty() {
+        File::create(notes_file)?.write_all(notes.as_bytes())?;
+        println!("Notes written to {}.", notes_file.display());
+    }
+    println!("Done! Add the rules in Karabiner-Elements (Complex Modifications → Add rule).");
+    Ok(())
+}
+
+/// Format the notes of an item as a section of a Markdown document
+fn notes_markdown(name: &str, notes: &json::Notes) -> String {
+    if notes.is_empty() {
+        return String::new();
+    }
+
+    let mut md = format!("## {}\n\n", name);
+    if !notes.appendix.is_empty() {
+        md += &format!("{}\n\n", notes.appendix);
+    }
+    for comment in &notes.comments {
+        md += &format!("{}\n\n", comment);
+    }
+    for autogen in &notes.autogens {
+        md += &format!("- `{}`: {}\n", autogen.autogen, autogen.comments.join("; ").replace('\n', " "));
+    }
+    if !notes.autogens.is_empty() {
+        md += "\n";
+    }
+    md
+}
+
 fn main() {
     try_main().unwrap();
 }
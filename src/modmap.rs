@@ -0,0 +1,110 @@
+//! Reading the modifier key remappings from macOS System Preferences
+//!
+//! They are stored in the global preferences as `com.apple.keyboard.modifiermapping.<vendor>-<product>-0`
+//! arrays of `{HIDKeyboardModifierMappingSrc, HIDKeyboardModifierMappingDst}` HID usages.
+
+use failure::Error;
+use plist::Value;
+
+use json;
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+const PREFIX: &str = "com.apple.keyboard.modifiermapping.";
+
+/// Modifier remappings imported from System Preferences
+#[derive(Debug)]
+pub struct ModifierMappings {
+    pub devices: Vec<DeviceMapping>,
+
+    /// Mappings that couldn't be imported
+    pub unknown: Vec<String>,
+}
+
+/// Modifier remappings for one keyboard
+#[derive(Debug)]
+pub struct DeviceMapping {
+    pub vendor_id: u64,
+    pub product_id: u64,
+    pub simple_modifications: Vec<json::SimpleModification>,
+}
+
+impl ModifierMappings {
+    /// Read a plist containing modifier mappings (such as the global preferences)
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ModifierMappings, Error> {
+        ModifierMappings::from_plist(&Value::from_file(path)?)
+    }
+
+    pub fn from_plist(plist: &Value) -> Result<ModifierMappings, Error> {
+        let root = plist.as_dictionary().ok_or_else(|| format_err!("Preferences file is not a dictionary"))?;
+
+        let mut mappings = ModifierMappings { devices: vec![], unknown: vec![] };
+        for (name, value) in root {
+            let device = match name.strip_prefix(PREFIX) {
+                Some(device) => device,
+                None => continue,
+            };
+            let mut ids = device.split('-').map(str::parse::<u64>);
+            let (vendor_id, product_id) = match (ids.next(), ids.next()) {
+                (Some(Ok(vendor_id)), Some(Ok(product_id))) => (vendor_id, product_id),
+                _ => bail!("Bad device in modifier mapping {}", name),
+            };
+
+            let mut device = DeviceMapping { vendor_id, product_id, simple_modifications: vec![] };
+            for mapping in value.as_array().ok_or_else(|| format_err!("Modifier mapping {} is not an array", name))? {
+                let mapping = mapping.as_dictionary().ok_or_else(|| format_err!("Bad modifier mapping in {}", name))?;
+                let usage = |key| mapping.get(key).and_then(Value::as_signed_integer);
+                let (src, dst) = match (usage("HIDKeyboardModifierMappingSrc"), usage("HIDKeyboardModifierMappingDst")) {
+                    (Some(src), Some(dst)) => (src, dst),
+                    _ => bail!("Bad modifier mapping in {}", name),
+                };
+
+                if src == dst {
+                    continue;
+                }
+                match (conv_usage(src), conv_usage(dst)) {
+                    (Some(from), Some(to)) =>
+                        device.simple_modifications.push(json::SimpleModification::new(from.into(), to.into())),
+                    _ => mappings.unknown.push(format!("{:#x} to {:#x} on device {}:{}", src, dst, vendor_id, product_id)),
+                }
+            }
+            mappings.devices.push(device);
+        }
+
+        Ok(mappings)
+    }
+
+    /// Keys that some devices map differently from others
+    ///
+    /// Those can't be put in the same list of simple modifications for all devices.
+    pub fn clashes(&self) -> BTreeSet<String> {
+        let mut clashes = BTreeSet::new();
+        for (i, device) in self.devices.iter().enumerate() {
+            for other in &self.devices[i + 1..] {
+                let conflicts = ::simple_modification_conflicts(&device.simple_modifications, &other.simple_modifications);
+                clashes.extend(conflicts.iter().map(|m| m.from.to_string()));
+            }
+        }
+        clashes
+    }
+}
+
+/// Convert a HID usage (page in the upper 32 bits) to a Karabiner-Elements key name
+fn conv_usage(usage: i64) -> Option<&'static str> {
+    Some(match usage {
+        -1 | 0x7_0000_0000 => "vk_none", // "No Action"
+        0x7_0000_0029 => "escape",
+        0x7_0000_0039 => "caps_lock",
+        0x7_0000_00e0 => "left_control",
+        0x7_0000_00e1 => "left_shift",
+        0x7_0000_00e2 => "left_option",
+        0x7_0000_00e3 => "left_command",
+        0x7_0000_00e4 => "right_control",
+        0x7_0000_00e5 => "right_shift",
+        0x7_0000_00e6 => "right_option",
+        0x7_0000_00e7 => "right_command",
+        0xff_0000_0003 => "fn",
+        _ => return None,
+    })
+}
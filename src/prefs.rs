@@ -0,0 +1,282 @@
+//! Reading Karabiner's preferences (`~/Library/Preferences/org.pqrs.Karabiner.plist`)
+//!
+//! Karabiner stores a list of profiles under `configList`, each with a name and an `identify` key.
+//! The settings of each profile live in a dictionary under that key (`sysctl` for the default
+//! profile), mapping item identifiers and parameter names to numbers. An item is enabled if its
+//! value is nonzero.
+//!
+//! Karabiner could also export the settings of a profile as a shell script, which we read here
+//! without running it.
+
+use failure::Error;
+use plist::{Dictionary, Value};
+
+use json;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Where Karabiner keeps its preferences (relative to the home directory)
+pub const DEFAULT_PATH: &str = "Library/Preferences/org.pqrs.Karabiner.plist";
+
+/// Karabiner's preferences file
+#[derive(Debug)]
+pub struct Preferences {
+    pub profiles: Vec<Profile>,
+
+    /// Index of the profile that was selected in Karabiner
+    pub selected: usize,
+}
+
+/// Karabiner profile
+#[derive(Debug, Default)]
+pub struct Profile {
+    /// Not known if there's only one profile, or for the profile an exported script starts with
+    pub name: Option<String>,
+
+    /// Item identifiers and parameter names, with their values
+    pub settings: BTreeMap<String, i64>,
+}
+
+impl Preferences {
+    /// Location of the preferences file in the user's home directory
+    pub fn default_path() -> Result<PathBuf, Error> {
+        let home = ::std::env::var_os("HOME").ok_or_else(|| format_err!("Can't find the home directory"))?;
+        Ok(Path::new(&home).join(DEFAULT_PATH))
+    }
+
+    /// Read the preferences file (XML or binary format)
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Preferences, Error> {
+        Preferences::from_plist(&Value::from_file(path)?)
+    }
+
+    pub fn from_plist(plist: &Value) -> Result<Preferences, Error> {
+        let root = plist.as_dictionary().ok_or_else(|| format_err!("Preferences file is not a dictionary"))?;
+
+        let mut profiles = vec![];
+        match root.get("configList").and_then(Value::as_array) {
+            Some(list) => {
+                for config in list {
+                    let config = config.as_dictionary().ok_or_else(|| format_err!("Bad profile in preferences file"))?;
+                    let identify = config.get("identify").and_then(Value::as_string).unwrap_or("sysctl");
+                    profiles.push(Profile {
+                        name: config.get("name").and_then(Value::as_string).map(String::from),
+                        settings: settings(root.get(identify)),
+                    });
+                }
+            }
+
+            // never created a second profile
+            None => profiles.push(Profile {
+                name: None,
+                settings: settings(root.get("sysctl")),
+            }),
+        }
+
+        let selected = root.get("configList_selectedIndex")
+                           .and_then(Value::as_signed_integer)
+                           .unwrap_or(0) as usize;
+        if selected >= profiles.len() {
+            bail!("Selected profile {} doesn't exist", selected);
+        }
+
+        Ok(Preferences { profiles, selected })
+    }
+
+    /// Read a shell script made by Karabiner's "Export" button
+    ///
+    /// The script is a series of `$cli enable <identifier>`, `$cli set <parameter> <value>` and
+    /// `$cli select_by_name <profile>` lines. Anything that doesn't call `$cli` is ignored.
+    pub fn parse_export_script(script: &str) -> Result<Preferences, Error> {
+        let mut prefs = Preferences {
+            // whichever profile was selected when the script runs
+            profiles: vec![Profile::default()],
+            selected: 0,
+        };
+
+        for (lineno, line) in script.lines().enumerate() {
+            let words = shell_words(line).map_err(|e| format_err!("line {}: {}", lineno + 1, e))?;
+            match words.first().map(|w| &**w) {
+                Some("$cli") | Some("${cli}") => {}
+                _ => continue,
+            }
+
+            let args = words[1..].iter().map(|w| &**w).collect::<Vec<_>>();
+            let settings = &mut prefs.profiles[prefs.selected].settings;
+            match *args {
+                ["enable", identifier] => { settings.insert(identifier.into(), 1); }
+                ["disable", identifier] => { settings.insert(identifier.into(), 0); }
+                ["set", name, value] => {
+                    let value = value.parse().map_err(|_| format_err!("line {}: bad value {} for {}", lineno + 1, value, name))?;
+                    settings.insert(name.into(), value);
+                }
+                ["select_by_name", name] => {
+                    prefs.selected = match prefs.profiles.iter().position(|p| p.name.as_deref() == Some(name)) {
+                        Some(i) => i,
+                        None => {
+                            prefs.profiles.push(Profile { name: Some(name.into()), settings: BTreeMap::new() });
+                            prefs.profiles.len() - 1
+                        }
+                    };
+                }
+                ["select", index] => {
+                    prefs.selected = index.parse().ok().filter(|&i| i < prefs.profiles.len())
+                                          .ok_or_else(|| format_err!("line {}: no profile {}", lineno + 1, index))?;
+                }
+                ["changed"] | ["reloadxml"] | ["relaunch"] => {}
+                _ => bail!("line {}: unknown command {}", lineno + 1, args.join(" ")),
+            }
+        }
+
+        Ok(prefs)
+    }
+
+    /// Read an exported shell script from a file
+    pub fn open_export_script<P: AsRef<Path>>(path: P) -> Result<Preferences, Error> {
+        Preferences::parse_export_script(&::std::fs::read_to_string(path)?)
+    }
+
+    /// Find a profile by name or index, or the selected one
+    pub fn profile(&self, name_or_index: Option<&str>) -> Result<&Profile, Error> {
+        let name = match name_or_index {
+            Some(name) => name,
+            None => return Ok(&self.profiles[self.selected]),
+        };
+        self.profiles.iter().find(|p| p.name.as_deref() == Some(name))
+            .or_else(|| name.parse().ok().and_then(|i: usize| self.profiles.get(i)))
+            .ok_or_else(|| {
+                let profiles = self.profiles.iter().enumerate().map(|(i, p)| format!("{}: {}", i, p.label())).collect::<Vec<_>>();
+                format_err!("No Karabiner profile {} (there are {})", name, profiles.join(", "))
+            })
+    }
+}
+
+impl Profile {
+    /// Name to show in messages
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or("(unnamed)")
+    }
+
+    /// Whether the item with this identifier is checked
+    pub fn is_enabled(&self, identifier: &str) -> bool {
+        self.settings.get(identifier).is_some_and(|&v| v != 0)
+    }
+
+    /// Identifiers of all checked items
+    pub fn enabled(&self) -> impl Iterator<Item=&str> {
+        self.settings.iter()
+            .filter(|&(name, &value)| value != 0 && !is_parameter(name))
+            .map(|(name, _)| &**name)
+    }
+
+    /// Copy parameters (key repeat, timeouts) to a Karabiner-Elements profile
+    ///
+    /// Returns the parameters that were set, and the ones with no equivalent.
+    pub fn apply_parameters(&self, profile: &mut json::Profile) -> (Vec<String>, Vec<String>) {
+        let mut applied = vec![];
+        let mut dropped = vec![];
+
+        for (name, &value) in &self.settings {
+            match PARAMETERS.iter().find(|p| p.0 == name) {
+                Some(&(_, parameter, to)) => {
+                    let value = value.max(0) as u64;
+                    let params = &mut profile.complex_modifications.parameters;
+                    let keyboard = &mut profile.virtual_hid_keyboard;
+                    match parameter {
+                        Parameter::InitialKeyRepeat => keyboard.initial_key_repeat_milliseconds = Some(value),
+                        Parameter::KeyRepeat => keyboard.key_repeat_milliseconds = Some(value),
+                        Parameter::ToIfAloneTimeout => params.to_if_alone_timeout_milliseconds = value,
+                        Parameter::ToIfHeldDownThreshold => params.to_if_held_down_threshold_milliseconds = value,
+                        Parameter::SimultaneousThreshold => params.simultaneous_threshold_milliseconds = value,
+                        Parameter::ToDelayedActionDelay => params.to_delayed_action_delay_milliseconds = value,
+                    }
+                    applied.push(format!("{} = {}", to, value));
+                }
+
+                None if is_parameter(name) => dropped.push(name.clone()),
+                None => {} // item identifier
+            }
+        }
+
+        (applied, dropped)
+    }
+}
+
+/// Where a parameter goes in Karabiner-Elements
+#[derive(Clone, Copy)]
+enum Parameter {
+    InitialKeyRepeat,
+    KeyRepeat,
+    ToIfAloneTimeout,
+    ToIfHeldDownThreshold,
+    SimultaneousThreshold,
+    ToDelayedActionDelay,
+}
+
+/// Karabiner parameters with Karabiner-Elements equivalents (both in milliseconds)
+const PARAMETERS: &[(&str, Parameter, &str)] = &[
+    ("repeat.initial_wait", Parameter::InitialKeyRepeat, "initial_key_repeat_milliseconds"),
+    ("repeat.wait", Parameter::KeyRepeat, "key_repeat_milliseconds"),
+    ("parameter.keyoverlaidmodifier_timeout", Parameter::ToIfAloneTimeout, "basic.to_if_alone_timeout_milliseconds"),
+    ("parameter.holdingkeytokey_wait", Parameter::ToIfHeldDownThreshold, "basic.to_if_held_down_threshold_milliseconds"),
+    ("parameter.simultaneouskeypresses_delay", Parameter::SimultaneousThreshold, "basic.simultaneous_threshold_milliseconds"),
+    ("parameter.keytokey_delayed_action_timeout", Parameter::ToDelayedActionDelay, "basic.to_delayed_action_delay_milliseconds"),
+];
+
+/// Settings starting with these are parameters rather than item identifiers
+const PARAMETER_PREFIXES: &[&str] = &["repeat.", "parameter.", "pointing."];
+
+/// Whether a setting is a parameter rather than an item identifier
+pub fn is_parameter(name: &str) -> bool {
+    PARAMETER_PREFIXES.iter().any(|p| name.starts_with(p))
+}
+
+/// Split a line of shell script into words, handling quotes and comments
+fn shell_words(line: &str) -> Result<Vec<String>, Error> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' if word.is_none() => break,
+            ' ' | '\t' => words.extend(word.take()),
+            '\'' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => w.push(c),
+                        None => bail!("unterminated quote"),
+                    }
+                }
+            }
+            '"' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => w.extend(chars.next()),
+                        Some(c) => w.push(c),
+                        None => bail!("unterminated quote"),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).extend(chars.next()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Read a profile's settings dictionary (ignoring anything that isn't a number)
+fn settings(dict: Option<&Value>) -> BTreeMap<String, i64> {
+    dict.and_then(Value::as_dictionary)
+        .map(Dictionary::iter)
+        .into_iter()
+        .flatten()
+        .filter_map(|(k, v)| v.as_signed_integer()
+                              .or_else(|| v.as_boolean().map(i64::from))
+                              .map(|v| (k.clone(), v)))
+        .collect()
+}
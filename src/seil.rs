@@ -0,0 +1,81 @@
+//! Reading settings from Seil (`~/Library/Preferences/org.pqrs.Seil.plist`), formerly PCKeyboardHack
+//!
+//! Seil remaps a fixed set of keys. For each key `foo` there is an `enable_foo` setting and a
+//! `keycode_foo` setting with the (macOS virtual) key code to send instead.
+
+use failure::Error;
+use plist::{Dictionary, Value};
+
+use json;
+
+use std::path::Path;
+
+/// Keys that Seil can remap (Seil's name and Karabiner-Elements' name)
+const KEYS: &[(&str, &str)] = &[
+    ("capslock",     "caps_lock"),
+    ("jis_kana",     "lang1"),
+    ("jis_eisuu",    "lang2"),
+    ("jis_nfer",     "japanese_pc_nfer"),
+    ("jis_xfer",     "japanese_pc_xfer"),
+    ("jis_katakana", "japanese_pc_katakana"),
+    ("command_l",    "left_command"),
+    ("command_r",    "right_command"),
+    ("control_l",    "left_control"),
+    ("control_r",    "right_control"),
+    ("option_l",     "left_option"),
+    ("option_r",     "right_option"),
+    ("shift_l",      "left_shift"),
+    ("shift_r",      "right_shift"),
+    ("fn",           "fn"),
+];
+
+/// Settings imported from Seil
+#[derive(Debug)]
+pub struct Seil {
+    pub simple_modifications: Vec<json::SimpleModification>,
+
+    /// Enabled settings that couldn't be imported
+    pub unknown: Vec<String>,
+}
+
+impl Seil {
+    /// Read Seil's (or PCKeyboardHack's) preferences file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Seil, Error> {
+        Seil::from_plist(&Value::from_file(path)?)
+    }
+
+    pub fn from_plist(plist: &Value) -> Result<Seil, Error> {
+        let root = plist.as_dictionary().ok_or_else(|| format_err!("Seil preferences file is not a dictionary"))?;
+        // newer versions keep the settings in a sub-dictionary
+        let settings = root.get("sysctl").and_then(Value::as_dictionary).unwrap_or(root);
+
+        let mut seil = Seil { simple_modifications: vec![], unknown: vec![] };
+        for (name, value) in settings {
+            let key = match name.strip_prefix("enable_") {
+                Some(key) if number(value) != Some(0) => key,
+                _ => continue,
+            };
+
+            match (KEYS.iter().find(|k| k.0 == key), keycode(settings, key)) {
+                (Some(&(_, from)), Some(to)) =>
+                    seil.simple_modifications.push(json::SimpleModification::new(from.into(), to)),
+                _ => seil.unknown.push(name.clone()),
+            }
+        }
+
+        Ok(seil)
+    }
+}
+
+/// The key code that Seil sends for a key
+///
+/// Seil uses macOS virtual key codes, so ones that aren't in our table can't be converted.
+fn keycode(settings: &Dictionary, key: &str) -> Option<json::KeyCodeValue> {
+    let raw = number(settings.get(&format!("keycode_{}", key))?)?;
+    let raw = if raw >= 0 { raw as u32 } else { return None };
+    ::conv_raw_key(raw).map(json::KeyCodeValue::from)
+}
+
+fn number(value: &Value) -> Option<i64> {
+    value.as_signed_integer().or_else(|| value.as_boolean().map(i64::from))
+}
@@ -1,26 +1,744 @@
 //! Structs necessary for deserializing Karabiner XML format
+//!
+//! The generic [`Document`] tree keeps the content and layout of the file (including comments and
+//! the DOCTYPE), so it can also be used to edit private.xml. It doesn't keep how the markup was
+//! spelled: character and entity references are written out as the characters they stand for
+//! (only `&`, `<` and `]]>` are escaped), attribute values are always double-quoted, and the
+//! whitespace inside tags is normalized.
+
+use failure::Error;
+use xml_rs::common::Position;
+use xml_rs::reader::{EventReader, ParserConfig, XmlEvent};
+
+use std::fmt;
+use std::io::Read;
+
+/// Whole XML file
+#[derive(Debug, Clone)]
+pub struct Document {
+    /// The `<?xml ...?>` declaration, as written
+    pub declaration: Option<String>,
+
+    /// Comments, processing instructions and the DOCTYPE before the root element
+    pub prolog: Vec<Node>,
+
+    pub root: Element,
+
+    /// Comments and processing instructions after the root element
+    pub epilog: Vec<Node>,
+
+    /// Whether the file ends with a newline
+    pub trailing_newline: bool,
+}
+
+/// Generic XML element
+#[derive(Debug, Clone)]
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+
+    /// Written as `<name/>` (only matters if there are no children)
+    pub self_closing: bool,
+}
+
+/// Anything that can be inside an element
+#[derive(Debug, Clone)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+    CData(String),
+    Comment(String),
+    ProcessingInstruction(String, Option<String>),
+
+    /// `<!DOCTYPE ...>`, as written
+    Doctype(String),
+}
+
+impl Document {
+    pub fn parse<R: Read>(mut r: R) -> Result<Document, Error> {
+        let mut source = String::new();
+        r.read_to_string(&mut source)?;
+        Document::parse_str(&source)
+    }
+
+    pub fn parse_str(source: &str) -> Result<Document, Error> {
+        let body = source.trim_start_matches('\u{feff}');
+        let declaration = if body.starts_with("<?xml") {
+            body.find("?>").map(|end| body[..end + 2].to_string())
+        } else {
+            None
+        };
+
+        let mut reader = EventReader::new_with_config(source.as_bytes(), ParserConfig::new().ignore_comments(false));
+        let mut stack: Vec<(Element, _)> = vec![];
+        let mut prolog = vec![];
+        let mut root = None;
+        let mut epilog = vec![];
+
+        loop {
+            let event = reader.next()?;
+            let position = reader.position();
+            let node = match event {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    stack.push((Element {
+                        name: qualified_name(name),
+                        attributes: attributes.into_iter().map(|a| (qualified_name(a.name), a.value)).collect(),
+                        children: vec![],
+                        self_closing: false,
+                    }, position));
+                    continue;
+                }
+
+                XmlEvent::EndElement { .. } => {
+                    let (mut element, start) = stack.pop().ok_or_else(|| format_err!("Unbalanced XML"))?;
+                    // xml-rs reports both ends of `<name/>` at the same place
+                    element.self_closing = element.children.is_empty() && start == position;
+                    if stack.is_empty() {
+                        root = Some(element);
+                        continue;
+                    }
+                    Node::Element(element)
+                }
+
+                XmlEvent::Characters(s) | XmlEvent::Whitespace(s) => Node::Text(s),
+                XmlEvent::CData(s) => Node::CData(s),
+                XmlEvent::Comment(s) => Node::Comment(s),
+                XmlEvent::ProcessingInstruction { name, data } => Node::ProcessingInstruction(name, data),
+                XmlEvent::StartDocument { .. } => continue,
+                XmlEvent::EndDocument => break,
+            };
+
+            match stack.last_mut() {
+                Some(&mut (ref mut parent, _)) => parent.children.push(node),
+                None if root.is_none() => prolog.push(node),
+                None => epilog.push(node),
+            }
+        }
+
+        // xml-rs doesn't report the DOCTYPE, so find it in the source
+        let mut prolog = prolog.into_iter().filter(|n| !n.is_whitespace()).collect::<Vec<_>>();
+        if let Some((index, doctype)) = find_doctype(body) {
+            prolog.insert(index.min(prolog.len()), Node::Doctype(doctype.to_string()));
+        }
+
+        Ok(Document {
+            declaration,
+            prolog,
+            root: root.ok_or_else(|| format_err!("No root element"))?,
+            epilog: epilog.into_iter().filter(|n| !n.is_whitespace()).collect(),
+            trailing_newline: source.ends_with('\n'),
+        })
+    }
+}
+
+impl Document {
+    /// Tidy up the document: re-indent it, normalize the spacing in autogens, and sort application
+    /// definitions
+    pub fn format(&mut self, indent: &str) {
+        format_element(&mut self.root, indent, 0);
+    }
+}
+
+/// Format an element and its descendants (`depth` is the element's indentation level)
+fn format_element(e: &mut Element, indent: &str, depth: usize) {
+    if e.name == "autogen" {
+        if let [Node::Text(ref mut text)] = *e.children {
+            *text = normalize_autogen(text);
+        }
+        return;
+    }
+
+    let has_children = e.children.iter().any(|n| matches!(*n, Node::Element(_) | Node::Comment(_)));
+    let has_text = e.children.iter().any(|n| matches!(*n, Node::CData(_)) || (n.is_text() && !n.is_whitespace()));
+
+    // leave text alone, including mixed content
+    if !has_children || has_text {
+        for child in &mut e.children {
+            if let Node::Element(ref mut child) = *child {
+                format_element(child, indent, depth + 1);
+            }
+        }
+        return;
+    }
+
+    let inner = indent.repeat(depth + 1);
+    let mut children = vec![];
+    let mut gap = String::new(); // whitespace before the next node
+    for mut node in e.children.drain(..) {
+        let is_comment = match node {
+            Node::Text(s) => {
+                gap.push_str(&s);
+                continue;
+            }
+            Node::Element(ref mut child) => {
+                format_element(child, indent, depth + 1);
+                false
+            }
+            Node::Comment(_) => true,
+            _ => false,
+        };
+
+        let whitespace = if children.is_empty() {
+            format!("\n{}", inner)
+        } else if is_comment && !gap.contains('\n') {
+            // keep comments on the same line as what they are about
+            " ".to_string()
+        } else if gap.matches('\n').count() > 1 {
+            format!("\n\n{}", inner)
+        } else {
+            format!("\n{}", inner)
+        };
+        children.push(Node::Text(whitespace));
+        children.push(node);
+        gap.clear();
+    }
+    children.push(Node::Text(format!("\n{}", indent.repeat(depth))));
+
+    sort_appdefs(&mut children);
+    e.children = children;
+}
+
+/// Put spaces after commas and around pipes in an autogen, and nowhere else
+fn normalize_autogen(autogen: &str) -> String {
+    let autogen = autogen.trim();
+    match ::autogen::Autogen::parse(autogen) {
+        Ok(ref parsed) if !parsed.args.is_empty() || parsed.text.is_empty() => parsed.to_string(),
+        _ => autogen.to_string(), // free text, or not something we understand
+    }
+}
+
+/// Sort each run of `<appdef>`s by name
+///
+/// An appdef moves together with the comments directly above it and a comment at the end of its
+/// line. A blank line or another element between two appdefs ends the run.
+fn sort_appdefs(children: &mut Vec<Node>) {
+    fn is_appdef(node: &Node) -> bool {
+        match *node {
+            Node::Element(ref e) => e.name == "appdef",
+            _ => false,
+        }
+    }
+    fn is_comment(node: &Node) -> bool {
+        matches!(*node, Node::Comment(_))
+    }
+    fn newlines(node: &Node) -> Option<usize> {
+        match *node {
+            Node::Text(ref s) if s.trim().is_empty() => Some(s.matches('\n').count()),
+            _ => None,
+        }
+    }
+
+    // find the appdefs with their comments: (first node, last node)
+    let mut units: Vec<(usize, usize)> = vec![];
+    for i in 0..children.len() {
+        if !is_appdef(&children[i]) {
+            continue;
+        }
+
+        let floor = units.last().map_or(0, |u| u.1 + 1);
+        let mut start = i;
+        while start >= floor + 2 && newlines(&children[start - 1]) == Some(1) && is_comment(&children[start - 2]) {
+            start -= 2;
+        }
+        let mut end = i;
+        if i + 2 < children.len() && newlines(&children[i + 1]) == Some(0) && is_comment(&children[i + 2]) {
+            end = i + 2;
+        }
+        units.push((start, end));
+    }
+
+    // group them into runs separated by single line breaks
+    let mut runs: Vec<Vec<(usize, usize)>> = vec![];
+    for unit in units {
+        let continues = runs.last().and_then(|run| run.last()).is_some_and(|prev| {
+            unit.0 == prev.1 + 2 && newlines(&children[prev.1 + 1]) == Some(1)
+        });
+        if continues {
+            runs.last_mut().unwrap().push(unit);
+        } else {
+            runs.push(vec![unit]);
+        }
+    }
+
+    // sort (starting from the end, so the indices stay valid)
+    for run in runs.into_iter().rev() {
+        let appname = |&(start, end): &(usize, usize)| {
+            children[start..=end].iter()
+                                 .filter_map(|n| match *n {
+                                     Node::Element(ref e) => e.child("appname").map(Element::text),
+                                     _ => None,
+                                 })
+                                 .next()
+                                 .unwrap_or_default()
+        };
+        let mut sorted = run.clone();
+        sorted.sort_by_key(&appname);
+        if sorted == run {
+            continue;
+        }
+
+        let mut replacement = vec![];
+        for (i, &(start, end)) in sorted.iter().enumerate() {
+            if i > 0 {
+                replacement.push(children[run[i - 1].1 + 1].clone());
+            }
+            replacement.extend(children[start..=end].iter().cloned());
+        }
+        let (first, last) = (run[0].0, run[run.len() - 1].1);
+        children.splice(first..=last, replacement);
+    }
+}
+
+/// Write the document back out
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // xml-rs doesn't report whitespace outside the root element, so put everything on its own line
+        if let Some(ref declaration) = self.declaration {
+            writeln!(f, "{}", declaration)?;
+        }
+        for node in &self.prolog {
+            writeln!(f, "{}", node)?;
+        }
+        write!(f, "{}", self.root)?;
+        for node in &self.epilog {
+            write!(f, "\n{}", node)?;
+        }
+        if self.trailing_newline {
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Element {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{}", self.name)?;
+        for (name, value) in &self.attributes {
+            write!(f, " {}=\"{}\"", name, escape(value, true))?;
+        }
+
+        if self.children.is_empty() && self.self_closing {
+            write!(f, "/>")
+        } else {
+            write!(f, ">")?;
+            for child in &self.children {
+                write!(f, "{}", child)?;
+            }
+            write!(f, "</{}>", self.name)
+        }
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Node::Element(ref e) => write!(f, "{}", e),
+            Node::Text(ref s) => write!(f, "{}", escape(s, false)),
+            Node::CData(ref s) => write!(f, "<![CDATA[{}]]>", s),
+            Node::Comment(ref s) => write!(f, "<!--{}-->", s),
+            Node::ProcessingInstruction(ref name, Some(ref data)) => write!(f, "<?{} {}?>", name, data),
+            Node::ProcessingInstruction(ref name, None) => write!(f, "<?{}?>", name),
+            Node::Doctype(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl Node {
+    pub fn is_text(&self) -> bool {
+        matches!(*self, Node::Text(_))
+    }
+
+    /// Whether this is text containing only whitespace
+    pub fn is_whitespace(&self) -> bool {
+        match *self {
+            Node::Text(ref s) => s.trim().is_empty(),
+            _ => false,
+        }
+    }
+}
+
+/// Find the DOCTYPE in the prolog, with the number of comments and processing instructions before it
+fn find_doctype(source: &str) -> Option<(usize, &str)> {
+    let mut rest = source;
+    let mut index = 0;
+    loop {
+        rest = rest.trim_start();
+        if rest.starts_with("<?xml") && rest.len() == source.len() {
+            rest = &rest[rest.find("?>")? + 2..];
+        } else if rest.starts_with("<!--") {
+            rest = &rest[rest.find("-->")? + 3..];
+            index += 1;
+        } else if rest.starts_with("<?") {
+            rest = &rest[rest.find("?>")? + 2..];
+            index += 1;
+        } else if rest.starts_with("<!DOCTYPE") {
+            break;
+        } else {
+            return None;
+        }
+    }
+
+    // the end is the first `>` outside quotes and the internal subset
+    let mut brackets = 0;
+    let mut quote = None;
+    for (i, c) in rest.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '[') => brackets += 1,
+            (None, ']') => brackets -= 1,
+            (None, '>') if brackets == 0 => return Some((index, &rest[..=i])),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Escape special characters in text or attribute values
+fn escape(s: &str, attribute: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' if !attribute && escaped.ends_with("]]") => escaped.push_str("&gt;"),
+            '"' if attribute => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn qualified_name(name: ::xml_rs::name::OwnedName) -> String {
+    match name.prefix {
+        Some(prefix) => format!("{}:{}", prefix, name.local_name),
+        None => name.local_name,
+    }
+}
+
+impl Element {
+    /// Parse a whole document, returning the root element
+    pub fn parse<R: Read>(r: R) -> Result<Element, Error> {
+        Ok(Document::parse(r)?.root)
+    }
+
+    /// Value of an attribute
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|a| a.0 == name).map(|a| &*a.1)
+    }
+
+    /// Child elements
+    pub fn elements(&self) -> impl Iterator<Item=&Element> {
+        self.children.iter().filter_map(|node| match *node {
+            Node::Element(ref e) => Some(e),
+            _ => None,
+        })
+    }
+
+    /// First child element with the given name
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|e| e.name == name)
+    }
+
+    /// Text content (trimmed)
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for node in &self.children {
+            match *node {
+                Node::Text(ref s) | Node::CData(ref s) => text.push_str(s),
+                _ => {}
+            }
+        }
+        text.trim().into()
+    }
+}
 
 /// Root element
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub struct Karabiner {
-    #[serde(rename="item")]
+    /// Rulesets (items nested in other items are listed separately)
     pub items: Vec<Item>,
+
+    /// Application definitions, for `<only>` and `<not>`
+    pub appdefs: Vec<AppDef>,
+
+    /// Vendor and product definitions, for `<device_only>` and `<device_not>`
+    pub devicedefs: Vec<DeviceDef>,
+
+    /// Input source definitions, for `<inputsource_only>` and `<inputsource_not>`
+    pub inputsourcedefs: Vec<InputSourceDef>,
+}
+
+impl Karabiner {
+    /// Parse private.xml
+    pub fn parse<R: Read>(r: R) -> Result<Karabiner, Error> {
+        Karabiner::from_element(&Element::parse(r)?)
+    }
+
+    pub fn from_element(root: &Element) -> Result<Karabiner, Error> {
+        fn walk(parent: &Element, karabiner: &mut Karabiner) -> Result<(), Error> {
+            for e in parent.elements() {
+                match &*e.name {
+                    "item" => {
+                        karabiner.items.push(Item::from_element(e)?);
+                        walk(e, karabiner)?;
+                    }
+                    "list" => walk(e, karabiner)?,
+                    "appdef" => karabiner.appdefs.push(AppDef::from_element(e)?),
+                    "devicevendordef" | "deviceproductdef" => karabiner.devicedefs.push(DeviceDef::from_element(e)?),
+                    "inputsourcedef" => karabiner.inputsourcedefs.push(InputSourceDef::from_element(e)?),
+                    _ => {}
+                }
+            }
+            Ok(())
+        }
+
+        let mut karabiner = Karabiner { items: vec![], appdefs: vec![], devicedefs: vec![], inputsourcedefs: vec![] };
+        walk(root, &mut karabiner)?;
+        Ok(karabiner)
+    }
 }
 
 /// Ruleset
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub struct Item {
     /// Short name (transferred to JSON)
     pub name: String,
 
-    /// Longer description (ignored)
+    /// Longer description (may be copied to JSON as notes)
     pub appendix: String,
 
-    /// Slug identifier (ignored)
-    pub identifier: String,
+    /// Slug identifier (used to check whether the item is enabled in the preferences)
+    pub identifier: Identifier,
+
+    /// Key replacements and filters (the item itself is the outermost block)
+    pub block: Block,
+}
+
+impl Item {
+    pub fn from_element(e: &Element) -> Result<Item, Error> {
+        Ok(Item {
+            name: e.child("name").ok_or_else(|| format_err!("Item without a name"))?.text(),
+            appendix: e.elements().filter(|c| c.name == "appendix").map(Element::text).collect::<Vec<_>>().join("\n"),
+            identifier: e.child("identifier").map(Identifier::from_element).unwrap_or_default(),
+            block: Block::from_element(e),
+        })
+    }
+}
+
+/// Slug identifier of an item
+#[derive(Debug, Default)]
+pub struct Identifier {
+    /// Set to "true" if the item is a virtual config, switched on and off by `VK_CONFIG_*` keys
+    pub vk_config: Option<String>,
+
+    pub name: String,
+}
+
+impl Identifier {
+    pub fn from_element(e: &Element) -> Identifier {
+        Identifier {
+            vk_config: e.attribute("vk_config").map(String::from),
+            name: e.text(),
+        }
+    }
+
+    pub fn is_vk_config(&self) -> bool {
+        self.vk_config.as_deref() == Some("true")
+    }
+}
+
+/// Group of key replacements sharing some filters
+#[derive(Debug, Default)]
+pub struct Block {
+    /// Conditions for the contents to apply (`<only>`, `<modifier_not>`, etc)
+    pub filters: Vec<Filter>,
+
+    /// Key replacements and nested blocks, in order
+    pub contents: Vec<BlockContent>,
+
+    /// Comments that aren't next to an autogen
+    pub comments: Vec<String>,
+}
+
+/// Something inside a block
+#[derive(Debug)]
+pub enum BlockContent {
+    Autogen(Autogen),
+    Block(Block),
+}
+
+/// A key replacement, such as `<autogen>--KeyToKey-- KeyCode::A, KeyCode::B</autogen>`
+#[derive(Debug)]
+pub struct Autogen {
+    pub text: String,
+
+    /// Comments on the lines before it or at the end of its line
+    pub comments: Vec<String>,
+}
+
+/// A filter element, such as `<only>TERMINAL</only>`
+#[derive(Debug, Clone)]
+pub struct Filter {
+    /// Element name
+    pub name: String,
+
+    /// Contents (usually a comma-delimited list)
+    pub value: String,
+}
+
+impl Block {
+    /// Collect the autogens, filters and nested blocks of an item or block element
+    ///
+    /// Comments are attached to the next autogen, or to the previous one if they are on the same line.
+    pub fn from_element(e: &Element) -> Block {
+        let mut block = Block::default();
+        let mut pending = vec![]; // comments waiting for an autogen
+        let mut same_line = None; // autogen at the end of the current line
+
+        for node in &e.children {
+            let child = match *node {
+                Node::Element(ref child) => child,
+
+                Node::Text(ref s) | Node::CData(ref s) => {
+                    if s.contains('\n') {
+                        same_line = None;
+                    }
+                    continue;
+                }
+
+                Node::ProcessingInstruction(..) | Node::Doctype(_) => continue,
+
+                Node::Comment(ref s) => {
+                    let comment = clean_comment(s);
+                    match same_line.and_then(|i| block.contents.get_mut(i)) {
+                        Some(&mut BlockContent::Autogen(ref mut autogen)) => autogen.comments.push(comment),
+                        _ => pending.push(comment),
+                    }
+                    continue;
+                }
+            };
 
-    /// Key replacements
-    #[serde(rename="autogen")]
-    pub keys: Vec<String>,
+            same_line = None;
+            match &*child.name {
+                "autogen" => {
+                    same_line = Some(block.contents.len());
+                    block.contents.push(BlockContent::Autogen(Autogen {
+                        text: child.text(),
+                        comments: pending.split_off(0),
+                    }));
+                }
+                "block" => {
+                    block.comments.append(&mut pending);
+                    block.contents.push(BlockContent::Block(Block::from_element(child)));
+                }
+                "name" | "appendix" | "identifier" => block.comments.append(&mut pending),
+                "item" | "list" => pending.clear(), // those comments are about the nested items
+                _ => {
+                    block.comments.append(&mut pending);
+                    block.filters.push(Filter { name: child.name.clone(), value: child.text() });
+                }
+            }
+        }
+
+        block.comments.append(&mut pending);
+        block
+    }
+
+    /// All autogens, including those in nested blocks
+    pub fn autogens(&self) -> Vec<&str> {
+        let mut autogens = vec![];
+        for content in &self.contents {
+            match *content {
+                BlockContent::Autogen(ref a) => autogens.push(&*a.text),
+                BlockContent::Block(ref b) => autogens.extend(b.autogens()),
+            }
+        }
+        autogens
+    }
+}
+
+/// Tidy up the text of a comment (trim each line and drop empty ones)
+fn clean_comment(s: &str) -> String {
+    s.lines().map(str::trim).filter(|l| !l.is_empty()).collect::<Vec<_>>().join("\n")
 }
 
+/// Application definition
+#[derive(Debug)]
+pub struct AppDef {
+    /// Name used in `<only>` and `<not>`
+    pub appname: String,
+
+    /// Bundle identifiers which match exactly
+    pub equal: Vec<String>,
+
+    /// Bundle identifier prefixes
+    pub prefix: Vec<String>,
+
+    /// Bundle identifier suffixes
+    pub suffix: Vec<String>,
+}
+
+impl AppDef {
+    pub fn from_element(e: &Element) -> Result<AppDef, Error> {
+        let texts = |name| e.elements().filter(|c| c.name == name).map(Element::text).collect();
+        Ok(AppDef {
+            appname: e.child("appname").ok_or_else(|| format_err!("Application definition without a name"))?.text(),
+            equal: texts("equal"),
+            prefix: texts("prefix"),
+            suffix: texts("suffix"),
+        })
+    }
+}
+
+/// Device vendor (`<devicevendordef>`) or product (`<deviceproductdef>`) definition
+#[derive(Debug)]
+pub struct DeviceDef {
+    /// Whether this is a product rather than a vendor
+    pub product: bool,
+
+    /// Name used after `DeviceVendor::` or `DeviceProduct::`
+    pub name: String,
+
+    pub id: u64,
+}
+
+impl DeviceDef {
+    pub fn from_element(e: &Element) -> Result<DeviceDef, Error> {
+        let product = e.name == "deviceproductdef";
+        let (name, id) = if product { ("productname", "productid") } else { ("vendorname", "vendorid") };
+        let name = e.child(name).ok_or_else(|| format_err!("<{}> without a name", e.name))?.text();
+        let id = e.child(id).ok_or_else(|| format_err!("<{}> {} without an ID", e.name, name))?.text();
+        let id = ::parse_number(&id).ok_or_else(|| format_err!("Bad ID {} for {}", id, name))?;
+        Ok(DeviceDef { product, name, id })
+    }
+}
+
+/// Input source definition
+#[derive(Debug)]
+pub struct InputSourceDef {
+    /// Name used in `<inputsource_only>` and `<inputsource_not>`
+    pub name: String,
+
+    pub languagecode: Option<String>,
+    pub inputsourceid_equal: Option<String>,
+    pub inputsourceid_prefix: Option<String>,
+    pub inputmodeid_equal: Option<String>,
+    pub inputmodeid_prefix: Option<String>,
+}
+
+impl InputSourceDef {
+    pub fn from_element(e: &Element) -> Result<InputSourceDef, Error> {
+        let text = |name| e.child(name).map(Element::text);
+        Ok(InputSourceDef {
+            name: text("name").ok_or_else(|| format_err!("Input source definition without a name"))?,
+            languagecode: text("languagecode"),
+            inputsourceid_equal: text("inputsourceid_equal"),
+            inputsourceid_prefix: text("inputsourceid_prefix"),
+            inputmodeid_equal: text("inputmodeid_equal"),
+            inputmodeid_prefix: text("inputmodeid_prefix"),
+        })
+    }
+}
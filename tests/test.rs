@@ -1,14 +1,41 @@
 #[macro_use] extern crate failure;
+extern crate karaconv;
+extern crate plist;
+#[macro_use] extern crate serde_json;
 extern crate tempdir;
 
 use failure::Error;
 use tempdir::TempDir;
 
+use std::collections::BTreeSet;
 use std::env;
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
+
+/// The karaconv binary built for these tests
+fn karaconv() -> Result<Command, Error> {
+    Ok(Command::new(Path::new(&env::var("CARGO_MANIFEST_DIR")?)
+                        .join("target")
+                        .join(&env::var("PROFILE")?)
+                        .join("karaconv")))
+}
+
+/// A private.xml item with one autogen, and filters (or other elements) before it
+///
+/// The identifier is `private.` followed by the name.
+fn item(name: &str, filters: &str, autogen: &str) -> String {
+    format!("<item><name>{0}</name><identifier>private.{0}</identifier>{1}<autogen>{2}</autogen></item>",
+            name, filters, autogen)
+}
+
+/// Convert the items of a private.xml snippet
+fn convert_items(items: &str) -> Result<Vec<karaconv::Converted>, Error> {
+    let inxml = karaconv::xml::Karabiner::parse(format!(r#"<?xml version="1.0"?><root>{}</root>"#, items).as_bytes())?;
+    let context = karaconv::Context::new(&inxml)?;
+    inxml.items.iter().map(|item| karaconv::convert_item(item, &context)).collect()
+}
 
 fn it_works_() -> Result<(), Error>{
     // copy private.xml and karabiner.before.json to a temporary folder
@@ -19,14 +46,17 @@ fn it_works_() -> Result<(), Error>{
              dir.path().join("karabiner.json"))?;
     
     // run conversion
-    assert!(Command::new(Path::new(&env::var("CARGO_MANIFEST_DIR")?)
-                              .join("target")
-                              .join(&env::var("PROFILE")?)
-                              .join("karaconv"))
+    assert!(karaconv()?
                     .arg("-i").arg(dir.path().join("private.xml"))
                     .arg("-o").arg(dir.path().join("karabiner.json"))
                     .status()?
                     .success());
+
+    // rules record the full path of private.xml, which depends on the temporary folder
+    let generated = fs::read_to_string(dir.path().join("karabiner.json"))?;
+    let infile = dir.path().join("private.xml").canonicalize()?;
+    assert!(generated.contains(&format!("\"file\": \"{}\"", infile.display())));
+    fs::write(dir.path().join("karabiner.json"), generated.replace(&*infile.to_string_lossy(), "private.xml"))?;
     
     // compare karabiner.json to karabiner.after.json and print diff
     fn run_jq(from: &Path, to: &Path) -> Result<PathBuf, Error> {
@@ -59,3 +89,1162 @@ fn it_works() {
     it_works_().unwrap();
 }
 
+
+fn xml_roundtrip_() -> Result<(), Error> {
+    // parsing and writing private.xml should not change it
+    let mut original = String::new();
+    File::open(Path::new(file!()).with_file_name("private.xml"))?.read_to_string(&mut original)?;
+    let doc = karaconv::xml::Document::parse_str(&original)?;
+    if doc.to_string() != original {
+        bail!("private.xml was not preserved");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn xml_roundtrip() {
+    xml_roundtrip_().unwrap();
+}
+
+
+fn autogen_parser_() -> Result<(), Error> {
+    use karaconv::autogen::Autogen;
+
+    // whitespace, newlines and a trailing comma are all fine
+    let autogen = Autogen::parse("--KeyToKey--\n  KeyCode::A,ModifierFlag::SHIFT_L|VK_COMMAND ,\n  KeyCode::B,\n")?;
+    assert_eq!(autogen.to_string(), "--KeyToKey-- KeyCode::A, ModifierFlag::SHIFT_L | VK_COMMAND, KeyCode::B");
+    let sections = autogen.sections()?;
+    assert_eq!(sections.len(), 1);
+    assert_eq!(sections[0].keys.len(), 2);
+    assert!(sections[0].keys[0].modifiers.is_some());
+    assert!(sections[0].keys[1].modifiers.is_none());
+
+    // malformed autogens are errors rather than panics
+    for bad in &["", "--KeyToKey", "--KeyToKey-- KeyCode::", "--KeyToKey-- KeyCode::A,, KeyCode::B",
+                 "--KeyToKey-- KeyCode::A KeyCode::B", "--KeyToKey-- A", "--KeyToKey-- KeyCode::A | KeyCode::B"] {
+        if Autogen::parse(bad).and_then(|a| a.sections().map(|_| ())).is_ok() {
+            bail!("{:?} should not parse", bad);
+        }
+    }
+
+    let error = Autogen::parse("--KeyToKey-- KeyCode::A,\n  KeyCode::B $").unwrap_err().to_string();
+    assert!(error.contains("line 2, column 14"), "{}", error);
+
+    Ok(())
+}
+
+#[test]
+fn autogen_parser() {
+    autogen_parser_().unwrap();
+}
+
+
+fn json_roundtrip_() -> Result<(), Error> {
+    // fields and formats from other versions of Karabiner-Elements should survive a round trip
+    let original = json!({
+        "global": {
+            "check_for_updates_on_startup": true,
+            "show_in_menu_bar": false,
+            "show_profile_name_in_menu_bar": false,
+            "ask_for_confirmation_before_quitting": false
+        },
+        "profiles": [
+            {
+                "name": "Old",
+                "selected": false,
+                "fn_function_keys": { "f1": "display_brightness_decrement" },
+                "devices": [],
+                "virtual_hid_keyboard": { "caps_lock_delay_milliseconds": 0, "keyboard_type": "ansi" },
+                "simple_modifications": { "caps_lock": "left_control" },
+                "complex_modifications": {
+                    "parameters": {
+                        "basic.simultaneous_threshold_milliseconds": 50,
+                        "basic.to_delayed_action_delay_milliseconds": 500,
+                        "basic.to_if_alone_timeout_milliseconds": 1000,
+                        "basic.to_if_held_down_threshold_milliseconds": 500,
+                        "mouse_motion_to_scroll.speed": 100
+                    },
+                    "rules": []
+                }
+            },
+            {
+                "name": "New",
+                "selected": true,
+                "fn_function_keys": [
+                    { "from": { "key_code": "f1" }, "to": [{ "consumer_key_code": "display_brightness_decrement" }] }
+                ],
+                "devices": [
+                    {
+                        "identifiers": { "vendor_id": 1452, "product_id": 834, "is_keyboard": true, "is_pointing_device": false },
+                        "ignore": false,
+                        "disable_built_in_keyboard_if_exists": true,
+                        "simple_modifications": [],
+                        "fn_function_keys": [],
+                        "manipulate_caps_lock_led": true
+                    }
+                ],
+                "virtual_hid_keyboard": { "keyboard_type_v2": "ansi", "country_code": 0 },
+                "simple_modifications": [
+                    { "from": { "key_code": "caps_lock" }, "to": [{ "key_code": "escape" }] },
+                    { "from": { "consumer_key_code": "mute" }, "to": [{ "pointing_button": "button1" }] }
+                ],
+                "complex_modifications": {
+                    "parameters": {
+                        "basic.simultaneous_threshold_milliseconds": 30,
+                        "basic.to_delayed_action_delay_milliseconds": 500,
+                        "basic.to_if_alone_timeout_milliseconds": 1000,
+                        "basic.to_if_held_down_threshold_milliseconds": 500,
+                        "mouse_motion_to_scroll.speed": 100
+                    },
+                    "rules": [
+                        {
+                            "description": "Shell",
+                            "enabled": false,
+                            "manipulators": [
+                                {
+                                    "type": "basic",
+                                    "from": { "key_code": "a", "modifiers": { "mandatory": ["command"] } },
+                                    "to": [{ "shell_command": "open -a Terminal" }],
+                                    "parameters": { "basic.to_if_alone_timeout_milliseconds": 500 }
+                                },
+                                {
+                                    "type": "basic",
+                                    "from": { "simultaneous": [{ "key_code": "j" }, { "key_code": "k" }] },
+                                    "to": [{ "key_code": "escape", "repeat": false, "halt": true }]
+                                },
+                                {
+                                    "type": "basic",
+                                    "from": { "consumer_key_code": "play_or_pause" },
+                                    "to": [
+                                        { "consumer_key_code": "mute", "modifiers": ["fn"] },
+                                        { "select_input_source": { "language": "^en$", "input_source_id": "com.apple.keylayout.US" } },
+                                        { "mouse_key": { "vertical_wheel": -64, "speed_multiplier": 2.5 } },
+                                        { "sticky_modifier": { "left_shift": "toggle" } },
+                                        { "software_function": { "open_application": { "bundle_identifier": "com.apple.Safari" } } },
+                                        { "set_variable": { "name": "mode", "value": 1 } },
+                                        { "set_notification_message": { "id": "mode", "text": "Mode" } },
+                                        { "pointing_button": "button1", "lazy": true, "hold_down_milliseconds": 50 }
+                                    ],
+                                    "to_if_alone": [{ "shell_command": "say hi", "halt": true }]
+                                },
+                                {
+                                    "type": "mouse_motion_to_scroll",
+                                    "conditions": [
+                                        { "type": "variable_if", "name": "scroll", "value": 1 },
+                                        { "type": "event_changed_if", "value": true }
+                                    ]
+                                }
+                            ]
+                        }
+                    ]
+                },
+                "parameters": { "delay_milliseconds_before_open_device": 1000 }
+            }
+        ]
+    });
+
+    let parsed: karaconv::json::Karabiner = serde_json::from_value(original.clone())?;
+    if !parsed.profiles[0].simple_modifications.object || parsed.profiles[1].simple_modifications.object {
+        bail!("simple_modifications format was not detected");
+    }
+    if parsed.profiles[1].keyboard(1452, 834).map(|d| d.disable_built_in_keyboard_if_exists) != Some(true) {
+        bail!("device settings were not read");
+    }
+    for manipulator in &parsed.profiles[1].complex_modifications.rules[0].manipulators {
+        if let Some(to) = manipulator.to.iter().chain(&manipulator.to_if_alone)
+                                                .find(|to| matches!(to, karaconv::json::To::Other(_))) {
+            bail!("event was not recognized: {:?}", to);
+        }
+    }
+    let written = serde_json::to_value(&parsed)?;
+    if written != original {
+        bail!("karabiner.json was not preserved:\n{:#}", written);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn json_roundtrip() {
+    json_roundtrip_().unwrap();
+}
+
+
+fn conditions_() -> Result<(), Error> {
+    use karaconv::environment::Environment;
+    use karaconv::json::{DeviceIdentifiers, Rule};
+
+    // which rules apply in Terminal on an HHKB?
+    let rules: Vec<Rule> = serde_json::from_value(json!([
+        {
+            "description": "Terminal on HHKB",
+            "manipulators": [{
+                "type": "basic",
+                "from": { "key_code": "a" },
+                "to": [{ "key_code": "b" }],
+                "conditions": [
+                    { "type": "frontmost_application_if", "bundle_identifiers": ["^com\\.apple\\.Terminal$"] },
+                    { "type": "device_if", "identifiers": [{ "vendor_id": 1278, "product_id": 33 }] },
+                    { "type": "variable_unless", "name": "vim_mode", "value": 1 }
+                ]
+            }]
+        },
+        {
+            "description": "Not in Terminal",
+            "manipulators": [{
+                "type": "basic",
+                "from": { "key_code": "a" },
+                "to": [{ "key_code": "b" }],
+                "conditions": [
+                    { "type": "frontmost_application_unless", "bundle_identifiers": ["^com\\.apple\\.Terminal$"] }
+                ]
+            }]
+        },
+        {
+            "description": "Japanese input",
+            "manipulators": [{
+                "type": "basic",
+                "from": { "key_code": "a" },
+                "to": [{ "key_code": "b" }],
+                "conditions": [
+                    { "type": "input_source_if", "input_sources": [{ "language": "^ja$" }] },
+                    { "type": "keyboard_type_if", "keyboard_types": ["jis"] }
+                ]
+            }]
+        }
+    ]))?;
+
+    let mut env = Environment {
+        bundle_identifier: Some("com.apple.Terminal".into()),
+        device: Some(DeviceIdentifiers { vendor_id: 1278, product_id: 33, is_keyboard: true, ..DeviceIdentifiers::default() }),
+        ..Environment::default()
+    };
+    let active = |env: &Environment| -> Result<Vec<String>, Error> {
+        let mut names = vec![];
+        for rule in &rules {
+            if rule.is_active(env)? {
+                names.push(rule.description.clone());
+            }
+        }
+        Ok(names)
+    };
+    assert_eq!(active(&env)?, vec!["Terminal on HHKB"]);
+
+    env.variables.insert("vim_mode".into(), json!(1));
+    assert!(active(&env)?.is_empty());
+
+    env.bundle_identifier = Some("com.apple.Safari".into());
+    assert_eq!(active(&env)?, vec!["Not in Terminal"]);
+
+    // typed conditions are written back unchanged
+    let written = serde_json::to_value(&rules[2])?;
+    if written["manipulators"][0]["conditions"][0] != json!({ "type": "input_source_if", "input_sources": [{ "language": "^ja$" }] }) {
+        bail!("condition was not preserved: {}", written);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn conditions() {
+    conditions_().unwrap();
+}
+
+
+fn simple_modifications_() -> Result<(), Error> {
+    use karaconv::json::SimpleModification;
+
+    let inxml = karaconv::xml::Karabiner::parse(&br#"<?xml version="1.0"?>
+        <root>
+            <item>
+                <name>Plain</name>
+                <identifier>private.plain</identifier>
+                <autogen>--KeyToKey-- KeyCode::CAPSLOCK, KeyCode::ESCAPE</autogen>
+                <autogen>--KeyToKey-- KeyCode::F13, KeyCode::VK_NONE</autogen>
+            </item>
+            <item>
+                <name>Shifted</name>
+                <identifier>private.shifted</identifier>
+                <autogen>--KeyToKey-- KeyCode::B, KeyCode::A, ModifierFlag::SHIFT_L</autogen>
+            </item>
+        </root>"#[..])?;
+    let context = karaconv::Context::new(&inxml)?;
+
+    let plain = karaconv::convert_item(&inxml.items[0], &context)?.simple_modifications();
+    let plain = plain.ok_or_else(|| format_err!("plain remaps were not simple"))?;
+    assert_eq!(plain, vec![SimpleModification::new("caps_lock".into(), "escape".into()),
+                           SimpleModification::new("f13".into(), "vk_none".into())]);
+    assert!(karaconv::convert_item(&inxml.items[1], &context)?.simple_modifications().is_none());
+
+    // an existing remap of the same key to something else is a conflict, the same remap isn't
+    let existing = vec![SimpleModification::new("caps_lock".into(), "left_control".into())];
+    assert_eq!(karaconv::simple_modification_conflicts(&existing, &plain).len(), 1);
+    assert!(karaconv::simple_modification_conflicts(&plain, &plain).is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn simple_modifications() {
+    simple_modifications_().unwrap();
+}
+
+
+fn asset_() -> Result<(), Error> {
+    // write private.xml as an asset file, leaving karabiner.json alone
+    let dir = TempDir::new("karaconv")?;
+    let asset = dir.path().join("private.json");
+    assert!(karaconv()?
+                    .arg("-i").arg(Path::new(file!()).with_file_name("private.xml"))
+                    .arg("--asset").arg(&asset)
+                    .arg("--asset-title").arg("My private.xml")
+                    .status()?
+                    .success());
+
+    let asset: karaconv::json::Asset = serde_json::from_reader(File::open(asset)?)?;
+    assert_eq!(asset.title, "My private.xml");
+    let after: karaconv::json::Karabiner = serde_json::from_reader(File::open(Path::new(file!()).with_file_name("karabiner.after.json"))?)?;
+    let descriptions = |rules: &[karaconv::json::Rule]| rules.iter().map(|r| r.description.clone()).collect::<BTreeSet<_>>();
+    // the same rules as in the profile
+    assert_eq!(descriptions(&asset.rules), descriptions(&after.profiles[0].complex_modifications.rules));
+
+    Ok(())
+}
+
+#[test]
+fn asset() {
+    asset_().unwrap();
+}
+
+fn import_asset_() -> Result<(), Error> {
+    // write private.xml as an asset file, then import one of its rules into karabiner.json
+    let dir = TempDir::new("karaconv")?;
+    let asset = dir.path().join("private.json");
+    let outfile = dir.path().join("karabiner.json");
+    fs::copy(Path::new(file!()).with_file_name("karabiner.before.json"), &outfile)?;
+    assert!(karaconv()?
+                    .arg("-i").arg(Path::new(file!()).with_file_name("private.xml"))
+                    .arg("--asset").arg(&asset)
+                    .status()?
+                    .success());
+    let rules: karaconv::json::Asset = serde_json::from_reader(File::open(&asset)?)?;
+    let name = rules.rules[0].description.clone();
+
+    let import = |rule: &str| -> Result<bool, Error> {
+        Ok(karaconv()?
+               .arg("import-asset").arg(&asset)
+               .arg("-o").arg(&outfile)
+               .arg("--rule").arg(rule)
+               .status()?
+               .success())
+    };
+    assert!(import(&name)?);
+    // importing again replaces the rule instead of adding a second copy
+    assert!(import(&name)?);
+    assert!(!import("no such rule")?);
+
+    let after: karaconv::json::Karabiner = serde_json::from_reader(File::open(&outfile)?)?;
+    let imported = after.profiles[0].complex_modifications.rules.iter().filter(|r| r.description == name).collect::<Vec<_>>();
+    assert_eq!(imported.len(), 1);
+    assert_eq!(serde_json::to_value(&imported[0].manipulators)?, serde_json::to_value(&rules.rules[0].manipulators)?);
+
+    Ok(())
+}
+
+#[test]
+fn import_asset() {
+    import_asset_().unwrap();
+}
+
+fn profiles_() -> Result<(), Error> {
+    // convert into the existing profile by name and into a copy of it made on the way
+    let dir = TempDir::new("karaconv")?;
+    let outfile = dir.path().join("karabiner.json");
+    fs::copy(Path::new(file!()).with_file_name("karabiner.before.json"), &outfile)?;
+    let convert = |args: &[&str]| -> Result<bool, Error> {
+        Ok(karaconv()?
+               .arg("-i").arg(Path::new(file!()).with_file_name("private.xml"))
+               .arg("-o").arg(&outfile)
+               .args(args)
+               .status()?
+               .success())
+    };
+
+    // unknown profiles aren't made up, and indexes are checked
+    assert!(!convert(&["-p", "Work"])?);
+    assert!(!convert(&["-p", "1"])?);
+
+    assert!(convert(&["-p", "Default profile", "-p", "Work", "--create-profile", "--from-profile", "0"])?);
+    let after: karaconv::json::Karabiner = serde_json::from_reader(File::open(&outfile)?)?;
+    assert_eq!(after.profiles.len(), 2);
+    assert_eq!(after.profiles[1].name, "Work");
+    assert!(!after.profiles[1].selected);
+    assert!(!after.profiles[0].complex_modifications.rules.is_empty());
+    assert_eq!(serde_json::to_value(&after.profiles[0].complex_modifications)?,
+               serde_json::to_value(&after.profiles[1].complex_modifications)?);
+
+    Ok(())
+}
+
+#[test]
+fn profiles() {
+    profiles_().unwrap();
+}
+
+fn merge_strategies_() -> Result<(), Error> {
+    use karaconv::{add_rule, Merge, Merged};
+
+    let rule = |description: &str, identifier: Option<&str>, key: &str| -> Result<karaconv::json::Rule, Error> {
+        let mut rule = json!({
+            "description": description,
+            "manipulators": [{"type": "basic", "from": {"key_code": key}, "to": [{"key_code": "escape"}]}],
+        });
+        if let Some(identifier) = identifier {
+            rule["karaconv_source"] = json!({"identifier": identifier});
+        }
+        Ok(serde_json::from_value(rule)?)
+    };
+
+    // renamed items replace their old rule
+    let mut rules = vec![rule("Old name", Some("private.a"), "a")?];
+    assert_eq!(add_rule(&mut rules, "New name", rule("New name", Some("private.a"), "a")?, Merge::Replace)?, Merged::Replaced);
+    assert_eq!(rules.len(), 1);
+    assert_eq!(rules[0].description, "New name");
+
+    // items with the same name but different identifiers get a rule each
+    assert_eq!(add_rule(&mut rules, "New name", rule("New name", Some("private.b"), "b")?, Merge::Replace)?, Merged::Added);
+    assert_eq!(rules.len(), 2);
+
+    // hand-written rules are matched by name, and reported
+    let mut rules = vec![rule("Caps", None, "caps_lock")?];
+    assert_eq!(add_rule(&mut rules, "Caps", rule("Caps", Some("private.caps"), "caps_lock")?, Merge::SkipExisting)?,
+               Merged::Skipped);
+    assert!(rules[0].karaconv_source.is_none());
+    assert!(add_rule(&mut rules, "Caps", rule("Caps", Some("private.caps"), "caps_lock")?, Merge::FailOnConflict).is_err());
+    assert_eq!(add_rule(&mut rules, "Caps", rule("Caps", Some("private.caps"), "a")?, Merge::AppendManipulators)?,
+               Merged::Appended(1));
+    assert_eq!(add_rule(&mut rules, "Caps", rule("Caps", Some("private.caps"), "a")?, Merge::AppendManipulators)?,
+               Merged::Appended(0));
+    assert_eq!(rules[0].manipulators.len(), 2);
+    assert_eq!(add_rule(&mut rules, "Caps", rule("Caps", Some("private.caps"), "b")?, Merge::Replace)?,
+               Merged::SkippedUntracked("Caps".into()));
+    assert_eq!(rules[0].manipulators.len(), 2);
+    assert_eq!(add_rule(&mut rules, "Caps", rule("Caps", Some("private.caps"), "b")?, Merge::ReplaceUntracked)?,
+               Merged::ReplacedUntracked("Caps".into()));
+    assert_eq!(rules[0].manipulators.len(), 1);
+    assert_eq!(rules.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn merge_strategies() {
+    merge_strategies_().unwrap();
+}
+
+fn prune_() -> Result<(), Error> {
+    let dir = TempDir::new("karaconv")?;
+    let infile = dir.path().join("private.xml");
+    let outfile = dir.path().join("karabiner.json");
+    fs::copy(Path::new(file!()).with_file_name("karabiner.before.json"), &outfile)?;
+    let convert = |items: &[&str], args: &[&str], answer: &[u8]| -> Result<(), Error> {
+        File::create(&infile)?.write_all(format!("<?xml version=\"1.0\"?><root>{}</root>", items.concat()).as_bytes())?;
+        let mut child = karaconv()?
+                            .arg("-i").arg(&infile)
+                            .arg("-o").arg(&outfile)
+                            .args(args)
+                            .stdin(Stdio::piped())
+                            .spawn()?;
+        child.stdin.take().unwrap().write_all(answer)?;
+        assert!(child.wait()?.success());
+        Ok(())
+    };
+    let descriptions = || -> Result<Vec<String>, Error> {
+        let json: karaconv::json::Karabiner = serde_json::from_reader(File::open(&outfile)?)?;
+        Ok(json.profiles[0].complex_modifications.rules.iter().map(|r| r.description.clone()).collect())
+    };
+
+    let caps = item("caps", "", "--KeyToKey-- KeyCode::CAPSLOCK, KeyCode::ESCAPE");
+    let a = item("a", "", "--KeyToKey-- KeyCode::A, KeyCode::B");
+    let b = item("b", "", "--KeyToKey-- KeyCode::B, KeyCode::A");
+    convert(&[&caps, &a, &b], &[], b"")?;
+    assert_eq!(descriptions()?, ["caps", "a", "b"]);
+
+    // edit the rule for b by hand, and add a rule of our own
+    let mut json: serde_json::Value = serde_json::from_reader(File::open(&outfile)?)?;
+    json["profiles"][0]["complex_modifications"]["rules"][2]["manipulators"][0]["to"][0]["key_code"] = json!("c");
+    let mine = json!({"description": "mine", "manipulators": []});
+    json["profiles"][0]["complex_modifications"]["rules"].as_array_mut().unwrap().push(mine);
+    serde_json::to_writer(File::create(&outfile)?, &json)?;
+
+    // a is removed, b is kept unless we say so
+    convert(&[&caps], &["--prune"], b"\n")?;
+    assert_eq!(descriptions()?, ["caps", "b", "mine"]);
+    convert(&[&caps], &["--prune"], b"y\n")?;
+    assert_eq!(descriptions()?, ["caps", "mine"]);
+
+    // appending manipulators keeps the hash up to date, so the rule is pruned without asking
+    let caps2 = item("caps", "", "--KeyToKey-- KeyCode::ESCAPE, KeyCode::CAPSLOCK");
+    convert(&[&caps2], &["--merge", "append-manipulators"], b"")?;
+    convert(&[], &["--prune"], b"")?;
+    assert_eq!(descriptions()?, ["mine"]);
+
+    Ok(())
+}
+
+#[test]
+fn prune() {
+    prune_().unwrap();
+}
+
+fn wait_after_from_key_() -> Result<(), Error> {
+    let converted = convert_items(&item("wait", "", "--KeyToKey-- KeyCode::A, KeyCode::VK_WAIT_100MS, KeyCode::B"))?;
+    assert_eq!(converted[0].losses, ["100ms wait after the key to remap (a) dropped"]);
+    Ok(())
+}
+
+#[test]
+fn wait_after_from_key() {
+    wait_after_from_key_().unwrap();
+}
+
+fn raw_key_codes_() -> Result<(), Error> {
+    let raw = |key: &str| item("raw", "", &format!("--KeyToKey-- KeyCode::RawValue::{}, KeyCode::A", key));
+    let converted = convert_items(&raw("0x35"))?;
+    assert_eq!(serde_json::to_value(&converted[0].rule.manipulators[0].from)?, json!({"key_code": "escape"}));
+    // a virtual key code isn't a HID usage, so unknown ones can't be passed through
+    assert!(convert_items(&raw("0x90")).is_err());
+    Ok(())
+}
+
+#[test]
+fn raw_key_codes() {
+    raw_key_codes_().unwrap();
+}
+
+fn keyboard_types_() -> Result<(), Error> {
+    assert_eq!(karaconv::collect_keyboard_types(Some("KeyboardType::JIS_MACBOOK_2008, KeyboardType::MACBOOK"), None)?,
+               ["ansi", "jis"]);
+    assert_eq!(karaconv::collect_keyboard_types(None, Some("KeyboardType::MACBOOK"))?, ["iso", "jis"]);
+    // models we don't know the layout of aren't guessed from the name
+    assert!(karaconv::collect_keyboard_types(Some("KeyboardType::ISO_MACBOOK_PRO"), None).is_err());
+    Ok(())
+}
+
+#[test]
+fn keyboard_types() {
+    keyboard_types_().unwrap();
+}
+
+fn filters_() -> Result<(), Error> {
+    let filtered = |filters: &str| item("filtered", filters, "--KeyToKey-- KeyCode::A, KeyCode::B");
+
+    let converted = convert_items(&filtered("<only>GOOGLE_CHROME</only><windowname_only>Gmail</windowname_only>"))?;
+    assert_eq!(converted[0].losses.len(), 1);
+    assert!(converted[0].losses[0].starts_with("window name filter (Gmail) dropped"));
+
+    // these would make the rule apply everywhere
+    assert!(convert_items(&filtered("<windowname_only>Gmail</windowname_only>")).is_err());
+    assert!(convert_items(&filtered("<windowname_onyl>Gmail</windowname_onyl>")).is_err());
+    Ok(())
+}
+
+#[test]
+fn filters() {
+    filters_().unwrap();
+}
+
+fn partial_keys_() -> Result<(), Error> {
+    let manipulator = |autogen: &str| -> Result<(serde_json::Value, Vec<String>), Error> {
+        let converted = convert_items(&item("partial", "", &format!("--KeyToKey-- KeyCode::F1, {}", autogen)))?;
+        Ok((serde_json::to_value(&converted[0].rule.manipulators[0])?, converted[0].losses.clone()))
+    };
+
+    // a held modifier becomes a modifier of the keys pressed meanwhile
+    let (shifted, losses) = manipulator("KeyCode::VK_PARTIAL_KEYDOWN, KeyCode::SHIFT_L, KeyCode::B, \
+                                         KeyCode::VK_PARTIAL_KEYUP, KeyCode::SHIFT_L")?;
+    assert_eq!(shifted["to"], json!([{"key_code": "b", "modifiers": ["left_shift"]}]));
+    assert!(losses.is_empty());
+
+    // another held key is pressed before them
+    let (held, losses) = manipulator("KeyCode::VK_PARTIAL_KEYDOWN, KeyCode::A, KeyCode::B, \
+                                      KeyCode::VK_PARTIAL_KEYUP, KeyCode::A")?;
+    assert_eq!(held["to"], json!([{"key_code": "a"}, {"key_code": "b"}]));
+    assert_eq!(losses.len(), 1);
+
+    // a release without a press happens when the origin key is released
+    let (released, losses) = manipulator("KeyCode::B, KeyCode::VK_PARTIAL_KEYUP, KeyCode::A")?;
+    assert_eq!(released["to"], json!([{"key_code": "b"}]));
+    assert_eq!(released["to_after_key_up"], json!([{"key_code": "a"}]));
+    assert_eq!(losses.len(), 1);
+    let (lone, _) = manipulator("KeyCode::VK_PARTIAL_KEYUP, KeyCode::A")?;
+    assert!(lone["to"].is_null());
+    assert_eq!(lone["to_after_key_up"], json!([{"key_code": "a"}]));
+
+    Ok(())
+}
+
+#[test]
+fn partial_keys() {
+    partial_keys_().unwrap();
+}
+
+fn unnamed_karabiner_profile_() -> Result<(), Error> {
+    use karaconv::prefs::Preferences;
+
+    // the profile that was selected when the script runs has no name we know of
+    let prefs = Preferences::parse_export_script("cli=/Applications/Karabiner.app/Contents/Library/bin/karabiner\n\
+                                                  $cli enable private.a\n\
+                                                  $cli select_by_name Work\n\
+                                                  $cli enable private.b\n")?;
+    assert_eq!(prefs.profiles.len(), 2);
+    assert_eq!(prefs.profiles[0].name, None);
+    assert!(prefs.profile(Some("Default")).is_err());
+    assert!(prefs.profile(Some("0"))?.is_enabled("private.a"));
+    assert!(prefs.profile(Some("Work"))?.is_enabled("private.b"));
+    Ok(())
+}
+
+#[test]
+fn unnamed_karabiner_profile() {
+    unnamed_karabiner_profile_().unwrap();
+}
+
+fn preferences_() -> Result<(), Error> {
+    use karaconv::prefs::Preferences;
+
+    let dir = TempDir::new("karaconv")?;
+    let xml = dir.path().join("org.pqrs.Karabiner.plist");
+    File::create(&xml)?.write_all(br#"<?xml version="1.0" encoding="UTF-8"?>
+        <!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+        <plist version="1.0">
+        <dict>
+            <key>configList</key>
+            <array>
+                <dict><key>name</key><string>Default</string><key>identify</key><string>sysctl</string></dict>
+                <dict><key>name</key><string>Work</string><key>identify</key><string>config_work</string></dict>
+            </array>
+            <key>configList_selectedIndex</key><integer>1</integer>
+            <key>sysctl</key>
+            <dict><key>private.a</key><integer>1</integer></dict>
+            <key>config_work</key>
+            <dict>
+                <key>private.a</key><integer>0</integer>
+                <key>private.b</key><true/>
+                <key>repeat.wait</key><integer>30</integer>
+                <key>parameter.mousekey_initial_wait</key><integer>100</integer>
+            </dict>
+        </dict>
+        </plist>"#)?;
+
+    // binary plists are read the same way
+    let binary = dir.path().join("binary.plist");
+    plist::Value::from_file(&xml)?.to_file_binary(&binary)?;
+
+    for path in &[xml, binary] {
+        let prefs = Preferences::open(path)?;
+        let work = prefs.profile(None)?;
+        assert_eq!(work.name.as_deref(), Some("Work"));
+        assert_eq!(work.enabled().collect::<Vec<_>>(), ["private.b"]);
+        assert!(prefs.profile(Some("Default"))?.is_enabled("private.a"));
+
+        let mut profile = karaconv::json::Profile::default();
+        let (applied, dropped) = work.apply_parameters(&mut profile);
+        assert_eq!(applied, ["key_repeat_milliseconds = 30"]);
+        assert_eq!(dropped, ["parameter.mousekey_initial_wait"]);
+        assert_eq!(profile.virtual_hid_keyboard.key_repeat_milliseconds, Some(30));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn preferences() {
+    preferences_().unwrap();
+}
+
+fn export_script_() -> Result<(), Error> {
+    use karaconv::prefs::Preferences;
+
+    let prefs = Preferences::parse_export_script(r#"#!/bin/sh
+cli=/Applications/Karabiner.app/Contents/Library/bin/karabiner
+
+$cli select_by_name 'My profile' # with a comment
+${cli} enable private.a
+$cli enable "private.\"quoted\""
+$cli enable private\ spaced
+$cli set repeat.wait 30
+$cli disable private.b
+$cli select 0
+$cli enable private.c
+/bin/echo "not karabiner" 'ignored'
+$cli changed
+"#)?;
+    assert_eq!(prefs.selected, 0);
+    let mine = prefs.profile(Some("My profile"))?;
+    assert_eq!(mine.enabled().collect::<Vec<_>>(), ["private spaced", "private.\"quoted\"", "private.a"]);
+    assert_eq!(mine.settings.get("repeat.wait"), Some(&30));
+    assert_eq!(mine.settings.get("private.b"), Some(&0));
+    assert!(prefs.profile(None)?.is_enabled("private.c"));
+
+    let error = |script| Preferences::parse_export_script(script).unwrap_err().to_string();
+    assert_eq!(error("$cli enable 'private.a"), "line 1: unterminated quote");
+    assert_eq!(error("\n$cli frobnicate"), "line 2: unknown command frobnicate");
+    assert_eq!(error("$cli set repeat.wait soon"), "line 1: bad value soon for repeat.wait");
+    assert_eq!(error("$cli select 3"), "line 1: no profile 3");
+    Ok(())
+}
+
+#[test]
+fn export_script() {
+    export_script_().unwrap();
+}
+
+fn seil_() -> Result<(), Error> {
+    use karaconv::json::SimpleModification;
+    use karaconv::seil::Seil;
+    use plist::{Dictionary, Value};
+
+    let mut settings = Dictionary::new();
+    for &(name, value) in &[("enable_capslock", 1), ("keycode_capslock", 0x35),
+                            ("enable_control_l", 0), ("keycode_control_l", 0x35),
+                            // not a virtual key code we know, so there's no way to tell which key it is
+                            ("enable_shift_r", 1), ("keycode_shift_r", 0x90),
+                            ("enable_mystery", 1), ("keycode_mystery", 0x35)] {
+        settings.insert(name.into(), Value::Integer(value.into()));
+    }
+    settings.insert("enable_option_r".into(), Value::Boolean(true));
+    settings.insert("keycode_option_r".into(), Value::Integer(0x3a.into()));
+
+    // older versions keep the settings at the top level, newer ones in a sub-dictionary
+    let mut nested = Dictionary::new();
+    nested.insert("sysctl".into(), Value::Dictionary(settings.clone()));
+    for plist in &[Value::Dictionary(settings), Value::Dictionary(nested)] {
+        let seil = Seil::from_plist(plist)?;
+        assert_eq!(seil.simple_modifications,
+                   [SimpleModification::new("caps_lock".into(), "escape".into()),
+                    SimpleModification::new("right_option".into(), "left_option".into())]);
+        assert_eq!(seil.unknown, ["enable_shift_r", "enable_mystery"]);
+    }
+    Ok(())
+}
+
+#[test]
+fn seil() {
+    seil_().unwrap();
+}
+
+/// Vendor and product ID of a keyboard, with its modifier mappings (source and destination HID usages)
+type Device<'a> = (u64, u64, &'a [(i64, i64)]);
+
+/// Global preferences with modifier mappings for some devices
+fn global_preferences(devices: &[Device]) -> plist::Value {
+    let mut root = plist::Dictionary::new();
+    for &(vendor, product, mappings) in devices {
+        let mappings = mappings.iter().map(|&(src, dst)| {
+            let mut mapping = plist::Dictionary::new();
+            mapping.insert("HIDKeyboardModifierMappingSrc".into(), plist::Value::Integer(src.into()));
+            mapping.insert("HIDKeyboardModifierMappingDst".into(), plist::Value::Integer(dst.into()));
+            plist::Value::Dictionary(mapping)
+        }).collect();
+        root.insert(format!("com.apple.keyboard.modifiermapping.{}-{}-0", vendor, product), plist::Value::Array(mappings));
+    }
+    root.insert("AppleLanguages".into(), plist::Value::Array(vec![]));
+    plist::Value::Dictionary(root)
+}
+
+fn modifier_mapping_clashes_() -> Result<(), Error> {
+    use karaconv::modmap::ModifierMappings;
+
+    const CAPS_LOCK: i64 = 0x7_0000_0039;
+    const ESCAPE: i64 = 0x7_0000_0029;
+    const LEFT_CONTROL: i64 = 0x7_0000_00e0;
+    let mappings = ModifierMappings::from_plist(&global_preferences(&[
+        (1452, 610, &[(CAPS_LOCK, ESCAPE), (LEFT_CONTROL, CAPS_LOCK)]),
+        (1133, 49948, &[(CAPS_LOCK, LEFT_CONTROL), (LEFT_CONTROL, CAPS_LOCK)]),
+    ]))?;
+    assert_eq!(mappings.clashes().into_iter().collect::<Vec<_>>(), ["caps_lock"]);
+    Ok(())
+}
+
+#[test]
+fn modifier_mapping_clashes() {
+    modifier_mapping_clashes_().unwrap();
+}
+
+fn modifier_mappings_() -> Result<(), Error> {
+    use karaconv::json::SimpleModification;
+    use karaconv::modmap::ModifierMappings;
+
+    let mappings = ModifierMappings::from_plist(&global_preferences(&[
+        (1452, 610, &[(0x7_0000_0039, 0x7_0000_00e0), // caps lock to control
+                      (0x7_0000_00e3, 0x7_0000_00e3), // unchanged
+                      (0xff_0000_0003, -1),           // fn to "No Action"
+                      (0x7_0000_00e2, 0x7_0000_0004)]), // not a modifier we know
+    ]))?;
+    assert_eq!(mappings.devices.len(), 1);
+    let device = &mappings.devices[0];
+    assert_eq!((device.vendor_id, device.product_id), (1452, 610));
+    assert_eq!(device.simple_modifications,
+               [SimpleModification::new("caps_lock".into(), "left_control".into()),
+                SimpleModification::new("fn".into(), "vk_none".into())]);
+    assert_eq!(mappings.unknown, ["0x7000000e2 to 0x700000004 on device 1452:610"]);
+    assert!(mappings.clashes().is_empty());
+
+    let mut bad = plist::Dictionary::new();
+    bad.insert("com.apple.keyboard.modifiermapping.apple-0".into(), plist::Value::Array(vec![]));
+    assert!(ModifierMappings::from_plist(&plist::Value::Dictionary(bad)).is_err());
+    Ok(())
+}
+
+#[test]
+fn modifier_mappings() {
+    modifier_mappings_().unwrap();
+}
+
+fn notes_in_description_() -> Result<(), Error> {
+    use karaconv::json::Rule;
+
+    let mut converted = convert_items("<item><name>Escape</name><appendix>Caps lock is escape</appendix>
+                                       <identifier>private.escape</identifier>
+                                       <autogen>--KeyToKey-- KeyCode::CAPSLOCK, KeyCode::ESCAPE</autogen></item>")?;
+    let mut converted = converted.remove(0);
+    converted.describe_notes();
+    assert_eq!(converted.rule.description, "Escape [notes: Caps lock is escape]");
+    assert!(karaconv::is_rule_for(&converted.rule, "Escape"));
+
+    // a rule written by hand that happens to start with the item name isn't the item's rule
+    let hand_written: Rule = serde_json::from_str(r#"{"description": "Escape -- my own version", "manipulators": []}"#)?;
+    assert!(!karaconv::is_rule_for(&hand_written, "Escape"));
+    Ok(())
+}
+
+#[test]
+fn notes_in_description() {
+    notes_in_description_().unwrap();
+}
+
+fn builtins_() -> Result<(), Error> {
+    let inxml = karaconv::xml::Karabiner::parse(&br#"<?xml version="1.0"?><root></root>"#[..])?;
+    let context = karaconv::Context::new(&inxml)?;
+    let builtins = karaconv::builtin::Builtins::load()?;
+    assert!(builtins.get("remap.controlL2controlL_escape").is_some());
+    for item in &builtins.items {
+        let converted = karaconv::convert_item(item, &context)?;
+        assert!(converted.losses.is_empty(), "{} loses {:?}", item.name, converted.losses);
+        assert!(!converted.rule.manipulators.is_empty(), "{} does nothing", item.name);
+    }
+    Ok(())
+}
+
+#[test]
+fn builtins() {
+    builtins_().unwrap();
+}
+
+fn fmt_backup_() -> Result<(), Error> {
+    let dir = TempDir::new("karaconv")?;
+    let file = dir.path().join("private.xml");
+    let original = "<?xml version=\"1.0\"?>\n<root><item><name>A</name></item></root>\n";
+    File::create(&file)?.write_all(original.as_bytes())?;
+    assert!(karaconv()?
+                    .arg("fmt").arg(&file)
+                    .stdout(Stdio::null())
+                    .status()?
+                    .success());
+
+    let backups = fs::read_dir(dir.path())?
+                      .map(|entry| Ok(entry?.path()))
+                      .collect::<Result<Vec<_>, Error>>()?
+                      .into_iter()
+                      .filter(|path| path.to_string_lossy().contains("private.xml.bak."))
+                      .collect::<Vec<_>>();
+    assert_eq!(backups.len(), 1);
+    assert_eq!(fs::read_to_string(&backups[0])?, original);
+    assert!(fs::read_to_string(&file)?.contains("\n    <item>\n        <name>A</name>\n    </item>\n"));
+    Ok(())
+}
+
+#[test]
+fn fmt_backup() {
+    fmt_backup_().unwrap();
+}
+
+fn xml_spelling_() -> Result<(), Error> {
+    // the DOCTYPE stays where it was, but references and quotes are written the standard way
+    let doc = karaconv::xml::Document::parse_str("<?xml version=\"1.0\"?>\n<!-- mine -->\n<!DOCTYPE root [\n  <!ENTITY e \"]>\">\n]>\n\
+                                                  <root a='x' b = \"y\">&#x41;&amp;&gt;&e;</root>\n")?;
+    assert_eq!(doc.to_string(), "<?xml version=\"1.0\"?>\n<!-- mine -->\n<!DOCTYPE root [\n  <!ENTITY e \"]>\">\n]>\n\
+                                 <root a=\"x\" b=\"y\">A&amp;>]></root>\n");
+    Ok(())
+}
+
+#[test]
+fn xml_spelling() {
+    xml_spelling_().unwrap();
+}
+
+fn format_xml_() -> Result<(), Error> {
+    let mut doc = karaconv::xml::Document::parse_str(r#"<?xml version="1.0"?>
+<root>
+<!-- apps -->
+<appdef><appname>VIM</appname></appdef> <!-- the editor -->
+<!-- terminals -->
+<appdef><appname>TERM</appname></appdef>
+<appdef><appname>BROWSER</appname></appdef>
+
+<appdef><appname>ALPHA</appname></appdef>
+<item><name>Keys</name>
+  <autogen>--KeyToKey--KeyCode::A,ModifierFlag::SHIFT_L|ModifierFlag::NONE ,KeyCode::B</autogen>
+      <autogen>  free   text  </autogen>
+</item>
+</root>
+"#)?;
+    doc.format("  ");
+    assert_eq!(doc.to_string(), r#"<?xml version="1.0"?>
+<root>
+  <appdef>
+    <appname>BROWSER</appname>
+  </appdef>
+  <!-- terminals -->
+  <appdef>
+    <appname>TERM</appname>
+  </appdef>
+  <!-- apps -->
+  <appdef>
+    <appname>VIM</appname>
+  </appdef> <!-- the editor -->
+
+  <appdef>
+    <appname>ALPHA</appname>
+  </appdef>
+  <item>
+    <name>Keys</name>
+    <autogen>--KeyToKey-- KeyCode::A, ModifierFlag::SHIFT_L | ModifierFlag::NONE, KeyCode::B</autogen>
+    <autogen>free   text</autogen>
+  </item>
+</root>
+"#);
+
+    // formatting again changes nothing
+    let formatted = doc.to_string();
+    doc.format("  ");
+    assert_eq!(doc.to_string(), formatted);
+    Ok(())
+}
+
+#[test]
+fn format_xml() {
+    format_xml_().unwrap();
+}
+
+fn builtin_and_notes_options_() -> Result<(), Error> {
+    let dir = TempDir::new("karaconv")?;
+    let infile = dir.path().join("private.xml");
+    let outfile = dir.path().join("karabiner.json");
+    fs::copy(Path::new(file!()).with_file_name("karabiner.before.json"), &outfile)?;
+    File::create(&infile)?.write_all(format!("<?xml version=\"1.0\"?><root>{}</root>",
+                                             item("caps", "<appendix>Caps lock is escape</appendix>",
+                                                  "--KeyToKey-- KeyCode::CAPSLOCK, KeyCode::ESCAPE")).as_bytes())?;
+    assert!(karaconv()?
+                    .arg("-i").arg(&infile)
+                    .arg("-o").arg(&outfile)
+                    .arg("--builtin").arg("remap.controlL2controlL_escape")
+                    .arg("--notes").arg("field")
+                    .stdout(Stdio::null())
+                    .status()?
+                    .success());
+
+    let after: serde_json::Value = serde_json::from_reader(File::open(&outfile)?)?;
+    let rules = after["profiles"][0]["complex_modifications"]["rules"].as_array().unwrap();
+    let rule = |description: &str| rules.iter().find(|r| r["description"] == description).cloned().unwrap_or_default();
+
+    // built-in settings remember their identifier, but not a file
+    let builtin = rule("Control_L to Control_L (+ When you type Control_L only, send Escape)");
+    assert_eq!(builtin["karaconv_source"]["identifier"], "remap.controlL2controlL_escape");
+    assert!(builtin["karaconv_source"]["file"].is_null());
+    assert_eq!(builtin["manipulators"][0]["to_if_alone"], json!([{"key_code": "escape"}]));
+
+    assert_eq!(rule("caps")["karaconv_notes"], json!({"appendix": "Caps lock is escape"}));
+    Ok(())
+}
+
+#[test]
+fn builtin_and_notes_options() {
+    builtin_and_notes_options_().unwrap();
+}
+
+fn excluded_modifiers_() -> Result<(), Error> {
+    use karaconv::allows_modifier;
+
+    // without optional modifiers, shift already stops the manipulator from matching
+    let converted = convert_items(&item("excluded", "<modifier_not>ModifierFlag::SHIFT_L</modifier_not>",
+                                        "--KeyToKey-- KeyCode::A, KeyCode::B"))?;
+    assert!(converted[0].losses.is_empty());
+
+    // these would let it match with shift held, so <modifier_not> would be lost
+    let optional = |modifiers: &[&str]| modifiers.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+    assert!(allows_modifier(&optional(&["any"]), "left_shift"));
+    assert!(allows_modifier(&optional(&["shift"]), "left_shift"));
+    assert!(allows_modifier(&optional(&["left_shift"]), "left_shift"));
+    assert!(!allows_modifier(&optional(&["right_shift", "left_control"]), "left_shift"));
+    assert!(!allows_modifier(&optional(&["ift"]), "left_shift"));
+    Ok(())
+}
+
+#[test]
+fn excluded_modifiers() {
+    excluded_modifiers_().unwrap();
+}
+
+fn device_and_input_source_filters_() -> Result<(), Error> {
+    let defs = "<devicevendordef><vendorname>LOGITECH</vendorname><vendorid>0x046d</vendorid></devicevendordef>
+                <deviceproductdef><productname>MX_KEYS</productname><productid>45915</productid></deviceproductdef>
+                <inputsourcedef><name>DVORAK</name><inputsourceid_equal>com.apple.keylayout.Dvorak</inputsourceid_equal></inputsourcedef>";
+    let filtered = |filters: &str| format!("{}{}", defs, item("filtered", filters, "--KeyToKey-- KeyCode::A, KeyCode::B"));
+
+    let converted = convert_items(&filtered("<device_only>DeviceVendor::LOGITECH, DeviceProduct::MX_KEYS,
+                                                          DeviceVendor::APPLE_COMPUTER, DeviceProduct::ANY,
+                                                          DeviceLocation::RawValue::0x10</device_only>
+                                              <inputsource_not>DVORAK, JAPANESE</inputsource_not>"))?;
+    assert!(converted[0].losses.is_empty());
+    assert_eq!(serde_json::to_value(&converted[0].rule.manipulators[0].conditions)?, json!([
+        {"type": "device_if", "identifiers": [{"vendor_id": 0x046d, "product_id": 45915},
+                                              {"vendor_id": 0x05ac, "location_id": 0x10}]},
+        {"type": "input_source_unless", "input_sources": [{"input_source_id": "^com\\.apple\\.keylayout\\.Dvorak$"},
+                                                          {"language": "^ja$"}]},
+    ]));
+
+    // names need a definition, like applications
+    let error = convert_items(&filtered("<device_not>DeviceVendor::NOBODY</device_not>")).err().map(|e| e.to_string());
+    assert_eq!(error.as_deref(), Some("Unknown device DeviceVendor::NOBODY (add a <devicevendordef> for it)"));
+    assert!(convert_items(&filtered("<inputsource_only>KLINGON</inputsource_only>")).is_err());
+    Ok(())
+}
+
+#[test]
+fn device_and_input_source_filters() {
+    device_and_input_source_filters_().unwrap();
+}
+
+fn simple_modifications_keep_other_rules_() -> Result<(), Error> {
+    let dir = TempDir::new("karaconv")?;
+    let infile = dir.path().join("private.xml");
+    let outfile = dir.path().join("karabiner.json");
+    File::create(&infile)?.write_all(format!("<?xml version=\"1.0\"?><root>{}</root>",
+                                             item("Mine", "", "--KeyToKey-- KeyCode::CAPSLOCK, KeyCode::ESCAPE")).as_bytes())?;
+    let mut json: serde_json::Value = serde_json::from_reader(File::open(Path::new(file!()).with_file_name("karabiner.before.json"))?)?;
+    let mine = json!({"description": "Mine", "manipulators": [{"type": "basic", "from": {"key_code": "f13"},
+                                                                "to": [{"key_code": "left_control"}],
+                                                                "to_if_alone": [{"key_code": "escape"}]}]});
+    json["profiles"][0]["complex_modifications"]["rules"] = json!([mine]);
+    json["profiles"][0]["simple_modifications"] = json!([]);
+    serde_json::to_writer(File::create(&outfile)?, &json)?;
+
+    let convert = |merge: &str| -> Result<bool, Error> {
+        Ok(karaconv()?
+               .arg("-i").arg(&infile)
+               .arg("-o").arg(&outfile)
+               .arg("--simple-modifications")
+               .arg("--merge").arg(merge)
+               .stdout(Stdio::null())
+               .status()?
+               .success())
+    };
+    let profile = || -> Result<karaconv::json::Profile, Error> {
+        let json: karaconv::json::Karabiner = serde_json::from_reader(File::open(&outfile)?)?;
+        Ok(json.profiles.into_iter().next().unwrap())
+    };
+    let has_simple = |profile: &karaconv::json::Profile| profile.simple_modifications.iter().any(|m| m.from.to_string() == "caps_lock");
+
+    // the hand-written rule with the same name stays, and so nothing is added
+    assert!(!convert("fail-on-conflict")?);
+    assert!(convert("replace")?);
+    assert!(convert("skip-existing")?);
+    let after = profile()?;
+    assert_eq!(after.complex_modifications.rules.len(), 1);
+    assert!(after.complex_modifications.rules[0].karaconv_source.is_none());
+    assert!(!has_simple(&after));
+
+    // unless we say it may go
+    assert!(convert("replace-untracked")?);
+    let after = profile()?;
+    assert!(after.complex_modifications.rules.is_empty());
+    assert!(has_simple(&after));
+    Ok(())
+}
+
+#[test]
+fn simple_modifications_keep_other_rules() {
+    simple_modifications_keep_other_rules_().unwrap();
+}
+
+fn asset_per_item_() -> Result<(), Error> {
+    let dir = TempDir::new("karaconv")?;
+    let infile = dir.path().join("private.xml");
+    let assets = dir.path().join("assets");
+    let unnamed = |name: &str| format!("<item><name>{}</name><autogen>--KeyToKey-- KeyCode::A, KeyCode::B</autogen></item>", name);
+    File::create(&infile)?.write_all(format!("<?xml version=\"1.0\"?><root>{}{}{}{}{}</root>",
+                                             item("caps", "", "--KeyToKey-- KeyCode::CAPSLOCK, KeyCode::ESCAPE"),
+                                             unnamed("First one"), unnamed("Second one"), unnamed("first one"),
+                                             unnamed("/")).as_bytes())?;
+    assert!(karaconv()?
+                    .arg("-i").arg(&infile)
+                    .arg("--asset").arg(&assets)
+                    .arg("--asset-per-item")
+                    .arg("--asset-title").arg("Mine")
+                    .stdout(Stdio::null())
+                    .status()?
+                    .success());
+
+    // items without an identifier are named after the item, and nothing is overwritten
+    let mut files = fs::read_dir(&assets)?
+                        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                        .collect::<Result<Vec<_>, Error>>()?;
+    files.sort();
+    assert_eq!(files, ["First_one.json", "Second_one.json", "_.json", "first_one-2.json", "private.caps.json"]);
+    let asset: karaconv::json::Asset = serde_json::from_reader(File::open(assets.join("first_one-2.json"))?)?;
+    assert_eq!(asset.title, "Mine: first one");
+    assert_eq!(asset.rules.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn asset_per_item() {
+    asset_per_item_().unwrap();
+}
+
+fn prune_other_files_() -> Result<(), Error> {
+    // two files with the same name in different folders
+    let dir = TempDir::new("karaconv")?;
+    let outfile = dir.path().join("karabiner.json");
+    fs::copy(Path::new(file!()).with_file_name("karabiner.before.json"), &outfile)?;
+    let convert = |folder: &str, items: &str, args: &[&str]| -> Result<(), Error> {
+        let infile = dir.path().join(folder).join("private.xml");
+        fs::create_dir_all(infile.parent().unwrap())?;
+        File::create(&infile)?.write_all(format!("<?xml version=\"1.0\"?><root>{}</root>", items).as_bytes())?;
+        assert!(karaconv()?
+                        .arg("-i").arg(&infile)
+                        .arg("-o").arg(&outfile)
+                        .args(args)
+                        .stdout(Stdio::null())
+                        .status()?
+                        .success());
+        Ok(())
+    };
+
+    convert("a", &item("caps", "", "--KeyToKey-- KeyCode::CAPSLOCK, KeyCode::ESCAPE"), &[])?;
+    convert("b", &item("a", "", "--KeyToKey-- KeyCode::A, KeyCode::B"), &[])?;
+    convert("a", "", &["--prune"])?;
+
+    let json: karaconv::json::Karabiner = serde_json::from_reader(File::open(&outfile)?)?;
+    let descriptions = json.profiles[0].complex_modifications.rules.iter().map(|r| &*r.description).collect::<Vec<_>>();
+    assert_eq!(descriptions, ["a"]);
+    Ok(())
+}
+
+#[test]
+fn prune_other_files() {
+    prune_other_files_().unwrap();
+}